@@ -0,0 +1,178 @@
+//! Bot detection, from the user agent and the client hints of the client.
+use anyhow::anyhow;
+
+use crate::exports::edgee::components::data_collection::Client;
+
+/// What to do with bot traffic, from the `bot_filtering` setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum BotFiltering {
+    /// Send bot traffic like any other traffic
+    #[default]
+    Off,
+    /// Don't send bot traffic
+    Drop,
+    /// Send bot traffic with the `bot=1` event parameter
+    Flag,
+    /// Send bot traffic with the `bot` traffic type (`tt`)
+    TrafficType,
+}
+
+impl BotFiltering {
+    pub(crate) fn parse(mode: &str) -> anyhow::Result<Self> {
+        match mode.trim() {
+            "" | "off" => Ok(BotFiltering::Off),
+            "drop" => Ok(BotFiltering::Drop),
+            "flag" => Ok(BotFiltering::Flag),
+            "traffic_type" => Ok(BotFiltering::TrafficType),
+            other => Err(anyhow!("Invalid bot_filtering: {other}")),
+        }
+    }
+}
+
+/// Traffic type of bot traffic, with the `traffic_type` mode.
+pub(crate) const BOT_TRAFFIC_TYPE: &str = "bot";
+
+/// Lowercase user agent substrings of crawlers, headless browsers, monitoring services and HTTP
+/// libraries. Generic words are anchored on the separators of User-Agent tokens, so that device
+/// names like CUBOT don't match.
+const BOT_SIGNATURES: [&str; 33] = [
+    "bot/",
+    "bot;",
+    "bot)",
+    "bot-",
+    "googlebot",
+    "bingbot",
+    "yandexbot",
+    "duckduckbot",
+    "applebot",
+    "crawler",
+    "spider",
+    "slurp",
+    "headlesschrome",
+    "phantomjs",
+    "lighthouse",
+    "pagespeed",
+    "gtmetrix",
+    "pingdom",
+    "uptimerobot",
+    "statuscake",
+    "site24x7",
+    "facebookexternalhit",
+    "google-inspectiontool",
+    "bingpreview",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "java/",
+    "axios/",
+    "node-fetch",
+    "+http",
+];
+
+/// Whether a client is a bot: a known bot signature, or one of the `patterns` (lowercase).
+/// Clients without a user agent (ex: server-side events) are not bots.
+pub(crate) fn is_bot(client: &Client, patterns: &[String]) -> bool {
+    let user_agent = client.user_agent.trim().to_lowercase();
+    !user_agent.is_empty()
+        && (BOT_SIGNATURES
+            .iter()
+            .any(|signature| user_agent.contains(signature))
+            || patterns
+                .iter()
+                .any(|pattern| user_agent.contains(pattern.as_str())))
+}
+
+/// First Chrome version sending the `Sec-CH-UA` client hint.
+const FIRST_CLIENT_HINTS_CHROME_VERSION: u32 = 89;
+
+/// Whether a recent Chromium based browser didn't send the client hints every Chrome sends, like
+/// scripts borrowing the user agent of a browser. Only checked with the `bot_require_client_hints`
+/// setting: browsers don't send client hints to plain HTTP sites.
+pub(crate) fn is_missing_client_hints(client: &Client) -> bool {
+    let user_agent = client.user_agent.to_lowercase();
+    client.user_agent_version_list.is_empty()
+        && client.user_agent_full_version_list.is_empty()
+        && chrome_version(&user_agent)
+            .is_some_and(|version| version >= FIRST_CLIENT_HINTS_CHROME_VERSION)
+}
+
+/// Major version of a Chromium based desktop or Android browser. ex: 124
+fn chrome_version(user_agent: &str) -> Option<u32> {
+    let (_, version) = user_agent.split_once("chrome/")?;
+    version.split('.').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::client;
+
+    const CHROME: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+    const SAFARI: &str = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1";
+
+    #[test]
+    fn browsers_are_not_bots() {
+        assert!(!is_bot(&client(CHROME), &[]));
+        assert!(!is_bot(&client(SAFARI), &[]));
+        assert!(!is_bot(
+            &client("Mozilla/5.0 (Linux; Android 10; CUBOT_X30) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36"),
+            &[]
+        ));
+        // server-side events, like refunds
+        assert!(!is_bot(&client(""), &[]));
+    }
+
+    #[test]
+    fn bots() {
+        assert!(is_bot(
+            &client("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"),
+            &[]
+        ));
+        assert!(is_bot(
+            &client("Mozilla/5.0 AppleWebKit/537.36 (KHTML, like Gecko; compatible; bingbot/2.0)"),
+            &[]
+        ));
+        assert!(is_bot(
+            &client("Mozilla/5.0 (compatible; SemrushBot/7~bl)"),
+            &[]
+        ));
+        assert!(is_bot(
+            &client(&CHROME.replace("Chrome/", "HeadlessChrome/")),
+            &[]
+        ));
+        assert!(is_bot(&client("curl/8.4.0"), &[]));
+        assert!(is_bot(
+            &client("AcmeMonitor/1.0"),
+            &["acmemonitor".to_string()]
+        ));
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(BotFiltering::parse("").unwrap(), BotFiltering::Off);
+        assert_eq!(
+            BotFiltering::parse(" traffic_type ").unwrap(),
+            BotFiltering::TrafficType
+        );
+        assert_eq!(
+            BotFiltering::parse("block").unwrap_err().to_string(),
+            "Invalid bot_filtering: block"
+        );
+    }
+
+    #[test]
+    fn missing_client_hints() {
+        let mut chrome = client(CHROME);
+        assert!(is_missing_client_hints(&chrome));
+        chrome.user_agent_version_list = "Chromium;124|Google Chrome;124".to_string();
+        assert!(!is_missing_client_hints(&chrome));
+        // browsers without client hints
+        assert!(!is_missing_client_hints(&client(SAFARI)));
+        assert!(!is_missing_client_hints(&client(
+            "Mozilla/5.0 (Linux) Chrome/80.0.3987.0 Safari/537.36"
+        )));
+        assert!(!is_missing_client_hints(&client("")));
+    }
+}
@@ -0,0 +1,332 @@
+//! Currency validation and conversion.
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+
+use crate::ga_payload::{GaPayload, Product};
+use crate::items::{parse_amount, round_amount};
+
+/// Active ISO 4217 currency codes.
+const ISO_4217: &[&str] = &[
+    "AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT",
+    "BGN", "BHD", "BIF", "BMD", "BND", "BOB", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD", "CAD",
+    "CDF", "CHF", "CLP", "CNY", "COP", "CRC", "CUP", "CVE", "CZK", "DJF", "DKK", "DOP", "DZD",
+    "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP", "GEL", "GHS", "GIP", "GMD", "GNF", "GTQ",
+    "GYD", "HKD", "HNL", "HTG", "HUF", "IDR", "ILS", "INR", "IQD", "IRR", "ISK", "JMD", "JOD",
+    "JPY", "KES", "KGS", "KHR", "KMF", "KPW", "KRW", "KWD", "KYD", "KZT", "LAK", "LBP", "LKR",
+    "LRD", "LSL", "LYD", "MAD", "MDL", "MGA", "MKD", "MMK", "MNT", "MOP", "MRU", "MUR", "MVR",
+    "MWK", "MXN", "MYR", "MZN", "NAD", "NGN", "NIO", "NOK", "NPR", "NZD", "OMR", "PAB", "PEN",
+    "PGK", "PHP", "PKR", "PLN", "PYG", "QAR", "RON", "RSD", "RUB", "RWF", "SAR", "SBD", "SCR",
+    "SDG", "SEK", "SGD", "SHP", "SLE", "SOS", "SRD", "SSP", "STN", "SYP", "SZL", "THB", "TJS",
+    "TMT", "TND", "TOP", "TRY", "TTD", "TWD", "TZS", "UAH", "UGX", "USD", "UYU", "UZS", "VES",
+    "VND", "VUV", "WST", "XAF", "XCD", "XOF", "XPF", "YER", "ZAR", "ZMW", "ZWL",
+];
+
+/// Event parameters holding an amount in the event currency.
+const MONETARY_PARAMETERS: [&str; 3] = ["value", "tax", "shipping"];
+
+/// Normalize a currency code (trimmed, uppercase). Returns None if it's not an ISO 4217 code.
+/// ex: " eur " -> "EUR"
+pub(crate) fn normalize_currency(code: &str) -> Option<String> {
+    let code = code.trim().to_uppercase();
+    if ISO_4217.contains(&code.as_str()) {
+        Some(code)
+    } else {
+        None
+    }
+}
+
+/// Static exchange rate table, to report revenue in the currency of the GA4 property.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExchangeRates {
+    /// Currency of the GA4 property. ex: EUR
+    pub reporting_currency: String,
+    /// Value of one unit of each currency, in the reporting currency. ex: USD => 0.92
+    rates: HashMap<String, f64>,
+}
+
+impl ExchangeRates {
+    /// Parse the `reporting_currency` and `exchange_rates` settings.
+    /// The table is a comma separated list of `CURRENCY:RATE`. ex: "USD:0.92,GBP:1.17"
+    pub(crate) fn parse(reporting_currency: &str, table: &str) -> anyhow::Result<Self> {
+        let reporting_currency = normalize_currency(reporting_currency)
+            .ok_or_else(|| anyhow!("Invalid reporting_currency: {}", reporting_currency.trim()))?;
+        let rates = table
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let rate = entry.split_once(':').and_then(|(currency, rate)| {
+                    let rate = parse_amount(rate).filter(|rate| *rate > 0.0)?;
+                    Some((normalize_currency(currency)?, rate))
+                });
+                rate.ok_or_else(|| anyhow!("Invalid exchange_rates: {}", entry.trim()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(ExchangeRates {
+            reporting_currency,
+            rates,
+        })
+    }
+
+    fn rate(&self, currency: &str) -> Option<f64> {
+        if currency == self.reporting_currency {
+            Some(1.0)
+        } else {
+            self.rates.get(currency).copied()
+        }
+    }
+
+    /// Conversion factor from one currency to another, if both are known.
+    pub(crate) fn factor(&self, from: &str, to: &str) -> Option<f64> {
+        Some(self.rate(from)? / self.rate(to)?)
+    }
+}
+
+/// Event parameter set when some items couldn't be converted into the event currency.
+const UNCONVERTED_CURRENCY_PARAMETER: &str = "unconverted_currency";
+
+/// Bring all the items into the event currency. Items without their own currency already use it.
+/// When the event has no currency, it takes the currency of the first item that has one.
+/// Items without an exchange rate keep their price and currency (`cu`), they are left out of the
+/// event value and the event is flagged with `unconverted_currency=1`.
+pub(crate) fn apply_item_currencies(
+    ga: &mut GaPayload,
+    items: &mut [Product],
+    rates: Option<&ExchangeRates>,
+) {
+    if ga.currency_code.is_none() {
+        ga.currency_code = items.iter().find_map(|item| item.currency.clone());
+    }
+    let Some(event_currency) = ga.currency_code.clone() else {
+        return;
+    };
+
+    let mut unconverted = false;
+    for item in items.iter_mut() {
+        // items in the event currency don't need their own currency
+        let Some(item_currency) = item.currency.take() else {
+            continue;
+        };
+        if item_currency == event_currency {
+            continue;
+        }
+        match rates.and_then(|rates| rates.factor(&item_currency, &event_currency)) {
+            Some(factor) => convert_item(item, factor),
+            None => {
+                item.currency = Some(item_currency);
+                unconverted = true;
+            }
+        }
+    }
+    if unconverted {
+        ga.event_parameter_string
+            .get_or_insert_with(HashMap::new)
+            .insert(UNCONVERTED_CURRENCY_PARAMETER.to_string(), "1".to_string());
+    }
+}
+
+/// Convert the event amounts and items into the reporting currency. The original currency and
+/// value are kept as `ep.original_currency` and `epn.original_value`.
+pub(crate) fn convert_to_reporting_currency(
+    ga: &mut GaPayload,
+    items: &mut [Product],
+    rates: &ExchangeRates,
+) {
+    let Some(event_currency) = ga.currency_code.clone() else {
+        return;
+    };
+    let Some(factor) = rates.factor(&event_currency, &rates.reporting_currency) else {
+        return;
+    };
+    if event_currency == rates.reporting_currency {
+        return;
+    }
+
+    let params = ga.event_parameter_number.get_or_insert_with(HashMap::new);
+    if let Some(value) = params.get("value").copied() {
+        params.insert("original_value".to_string(), value);
+    }
+    for key in MONETARY_PARAMETERS {
+        if let Some(amount) = params.get_mut(key) {
+            *amount = round_amount(*amount * factor);
+        }
+    }
+    ga.event_parameter_string
+        .get_or_insert_with(HashMap::new)
+        .insert("original_currency".to_string(), event_currency);
+    ga.currency_code = Some(rates.reporting_currency.clone());
+
+    // items still in their own currency have no exchange rate
+    for item in items.iter_mut().filter(|item| item.currency.is_none()) {
+        convert_item(item, factor);
+    }
+}
+
+fn convert_item(item: &mut Product, factor: f64) {
+    for amount in [&mut item.price, &mut item.discount] {
+        if let Some(value) = amount.as_deref().and_then(parse_amount) {
+            *amount = Some(round_amount(value * factor).to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn rates() -> ExchangeRates {
+        ExchangeRates::parse("eur", "USD:0.5, gbp:1.25,").unwrap()
+    }
+
+    fn product(price: &str, currency: Option<&str>) -> Product {
+        Product {
+            price: Some(price.to_string()),
+            currency: currency.map(String::from),
+            ..Product::default()
+        }
+    }
+
+    #[test]
+    fn normalize_currency_codes() {
+        assert_eq!(normalize_currency("eur"), Some("EUR".to_string()));
+        assert_eq!(normalize_currency(" Usd "), Some("USD".to_string()));
+        assert_eq!(normalize_currency("EURO"), None);
+        assert_eq!(normalize_currency("XXX"), None);
+        assert_eq!(normalize_currency(""), None);
+    }
+
+    #[test]
+    fn parse_exchange_rates() {
+        let rates = rates();
+        assert_eq!(rates.reporting_currency, "EUR");
+        assert_eq!(
+            rates.rates,
+            HashMap::from([("USD".to_string(), 0.5), ("GBP".to_string(), 1.25)])
+        );
+    }
+
+    #[test]
+    fn invalid_exchange_rates() {
+        let error = |currency: &str, table: &str| {
+            ExchangeRates::parse(currency, table)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error("euro", "USD:0.5"), "Invalid reporting_currency: euro");
+        assert_eq!(error("", ""), "Invalid reporting_currency: ");
+        assert_eq!(
+            error("EUR", "USD:0.5,XXX:2"),
+            "Invalid exchange_rates: XXX:2"
+        );
+        assert_eq!(error("EUR", "JPY:abc"), "Invalid exchange_rates: JPY:abc");
+        assert_eq!(error("EUR", "CHF:-1"), "Invalid exchange_rates: CHF:-1");
+        assert_eq!(error("EUR", "USD=0.9"), "Invalid exchange_rates: USD=0.9");
+    }
+
+    #[test]
+    fn conversion_factors() {
+        let rates = rates();
+        assert_eq!(rates.factor("USD", "EUR"), Some(0.5));
+        assert_eq!(rates.factor("EUR", "USD"), Some(2.0));
+        assert_eq!(rates.factor("GBP", "USD"), Some(2.5));
+        assert_eq!(rates.factor("JPY", "EUR"), None);
+    }
+
+    #[test]
+    fn event_takes_the_item_currency() {
+        let mut ga = GaPayload::default();
+        let mut items = vec![product("10", None), product("20", Some("GBP"))];
+        apply_item_currencies(&mut ga, &mut items, None);
+
+        assert_eq!(ga.currency_code.as_deref(), Some("GBP"));
+        assert_eq!(items[1].price.as_deref(), Some("20"));
+        assert_eq!(items[1].custom_parameters, None);
+    }
+
+    #[test]
+    fn items_are_converted_to_the_event_currency() {
+        let mut ga = GaPayload::default();
+        ga.currency_code = Some("USD".to_string());
+        let mut items = vec![
+            product("10", Some("GBP")),
+            product("20", Some("JPY")),
+            product("30", Some("USD")),
+            product("40", Some("JPY")),
+        ];
+        apply_item_currencies(&mut ga, &mut items, Some(&rates()));
+
+        assert_eq!(items[0].price.as_deref(), Some("25"));
+        assert_eq!(items[0].currency, None);
+        // no exchange rate: the item keeps its price and currency
+        assert_eq!(items[1].price.as_deref(), Some("20"));
+        assert_eq!(items[1].currency.as_deref(), Some("JPY"));
+        assert_eq!(items[1].custom_parameters, None);
+        assert_eq!(items[2].price.as_deref(), Some("30"));
+        assert_eq!(items[2].currency, None);
+        assert_eq!(
+            ga.event_parameter_string,
+            Some(HashMap::from([(
+                "unconverted_currency".to_string(),
+                "1".to_string()
+            )]))
+        );
+    }
+
+    #[test]
+    fn converted_items_are_not_flagged() {
+        let mut ga = GaPayload::default();
+        ga.currency_code = Some("USD".to_string());
+        let mut items = vec![product("10", Some("GBP")), product("30", None)];
+        apply_item_currencies(&mut ga, &mut items, Some(&rates()));
+        assert_eq!(ga.event_parameter_string, None);
+    }
+
+    #[test]
+    fn event_is_converted_to_the_reporting_currency() {
+        let mut ga = GaPayload::default();
+        ga.currency_code = Some("USD".to_string());
+        ga.event_parameter_number = Some(HashMap::from([
+            ("value".to_string(), 30.0),
+            ("tax".to_string(), 3.0),
+            ("step".to_string(), 2.0),
+        ]));
+        let mut items = vec![product("10.1", None), product("1000", Some("JPY"))];
+        convert_to_reporting_currency(&mut ga, &mut items, &rates());
+
+        assert_eq!(ga.currency_code.as_deref(), Some("EUR"));
+        assert_eq!(
+            ga.event_parameter_number,
+            Some(HashMap::from([
+                ("value".to_string(), 15.0),
+                ("original_value".to_string(), 30.0),
+                ("tax".to_string(), 1.5),
+                ("step".to_string(), 2.0),
+            ]))
+        );
+        assert_eq!(
+            ga.event_parameter_string,
+            Some(HashMap::from([(
+                "original_currency".to_string(),
+                "USD".to_string()
+            )]))
+        );
+        assert_eq!(items[0].price.as_deref(), Some("5.05"));
+        assert_eq!(items[1].price.as_deref(), Some("1000"));
+    }
+
+    #[test]
+    fn unknown_rates_are_not_converted() {
+        let mut ga = GaPayload::default();
+        ga.currency_code = Some("JPY".to_string());
+        ga.event_parameter_number = Some(HashMap::from([("value".to_string(), 1000.0)]));
+        convert_to_reporting_currency(&mut ga, &mut [], &rates());
+
+        assert_eq!(ga.currency_code.as_deref(), Some("JPY"));
+        assert_eq!(
+            ga.event_parameter_number,
+            Some(HashMap::from([("value".to_string(), 1000.0)]))
+        );
+        assert_eq!(ga.event_parameter_string, None);
+    }
+}
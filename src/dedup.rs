@@ -0,0 +1,106 @@
+//! Deduplication of transactions sent more than once (ex: reloads of the thank-you page).
+//!
+//! Components don't have any storage to remember the transactions already sent, so duplicates
+//! can't be dropped here, nor can the time between two duplicates be limited. Instead, the hits of
+//! purchases younger than a maximum age (the time between the event and its processing) get an
+//! `event_id` and `_rnd` derived from the transaction ID, so that they can be deduplicated
+//! downstream. Hits of older purchases (ex: replays, imports) keep their own IDs.
+use crate::ga_payload::GaPayload;
+
+/// Events deduplicated on their `transaction_id`.
+pub(crate) const TRANSACTION_EVENTS: [&str; 1] = ["purchase"];
+
+/// Whether a purchase that happened at `timestamp_micros` and is sent at `now_micros` is at most
+/// `max_age` seconds old.
+pub(crate) fn is_recent_transaction(timestamp_micros: i64, now_micros: i64, max_age: u64) -> bool {
+    let max_age_micros = i64::try_from(max_age)
+        .unwrap_or(i64::MAX)
+        .saturating_mul(1_000_000);
+    now_micros.saturating_sub(timestamp_micros) <= max_age_micros
+}
+
+/// Derive the `event_id` and `_rnd` of a transaction hit from the measurement ID and the
+/// transaction ID.
+pub(crate) fn dedupe_transaction(ga: &mut GaPayload, measurement_id: &str, transaction_id: &str) {
+    let digest = md5::compute(format!("{measurement_id}:{transaction_id}"));
+
+    let event_id = uuid::Builder::from_md5_bytes(digest.0)
+        .into_uuid()
+        .to_string();
+    ga.event_parameter_string
+        .get_or_insert_with(Default::default)
+        .insert("event_id".to_string(), event_id);
+
+    // same range as the random number set by gtag.js: [0, 2147483647]
+    let rnd = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) & 0x7fff_ffff;
+    ga.gclid_deduper = Some(rnd.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dedupe(transaction_id: &str) -> (String, String) {
+        let mut ga = GaPayload::default();
+        dedupe_transaction(&mut ga, "G-1", transaction_id);
+        (
+            ga.event_parameter_string.unwrap()["event_id"].clone(),
+            ga.gclid_deduper.unwrap(),
+        )
+    }
+
+    #[test]
+    fn same_transaction_is_identical() {
+        assert_eq!(dedupe("T-1"), dedupe("T-1"));
+        assert_ne!(dedupe("T-1"), dedupe("T-2"));
+    }
+
+    #[test]
+    fn recent_transactions() {
+        let purchase = 1_735_689_599_500_000;
+        assert!(is_recent_transaction(purchase, purchase + 1_000_000, 600));
+        assert!(is_recent_transaction(purchase, purchase + 600_000_000, 600));
+        assert!(!is_recent_transaction(
+            purchase,
+            purchase + 600_000_001,
+            600
+        ));
+        assert!(is_recent_transaction(purchase, purchase - 5_000_000, 600));
+        assert!(is_recent_transaction(0, i64::MAX, u64::MAX));
+    }
+
+    /// IDs of a purchase hit, like `track` sets them with a maximum age of 600 seconds.
+    fn hit_ids(
+        transaction_id: &str,
+        timestamp_micros: i64,
+        now_micros: i64,
+    ) -> Option<(String, String)> {
+        is_recent_transaction(timestamp_micros, now_micros, 600).then(|| dedupe(transaction_id))
+    }
+
+    #[test]
+    fn deduplicated_hits() {
+        let purchase = 1_735_689_600_000_000;
+        let hour = 3_600_000_000;
+        let first = hit_ids("T-1", purchase, purchase + 2_000_000);
+        assert!(first.is_some());
+        // reload of the thank-you page, sent right away: same IDs
+        assert_eq!(hit_ids("T-1", purchase + hour, purchase + hour), first);
+        // the time between duplicates isn't limited: a reload the next day has the same IDs
+        assert_eq!(
+            hit_ids("T-1", purchase + 24 * hour, purchase + 24 * hour),
+            first
+        );
+        // the first hit replayed an hour later is too old: it keeps its own IDs
+        assert_eq!(hit_ids("T-1", purchase, purchase + hour), None);
+        // another transaction
+        assert_ne!(hit_ids("T-2", purchase, purchase), first);
+    }
+
+    #[test]
+    fn rnd_is_a_positive_31_bit_number() {
+        let (event_id, rnd) = dedupe("T-1");
+        assert!(uuid::Uuid::parse_str(&event_id).is_ok());
+        assert!(rnd.parse::<u32>().unwrap() <= 2_147_483_647);
+    }
+}
@@ -0,0 +1,156 @@
+//! Event time. Hits are sent when Edgee processes the event, which can be long after the event
+//! happened (ex: events queued on the device while offline, replayed events). Delayed hits are sent
+//! with their queue time (`qt`), and events older than the 72 hours GA4 accepts are flagged or
+//! rejected, instead of being recorded at the wrong time.
+//!
+//! Edgee timestamps are Unix times, so the delay doesn't depend on the timezone of the client.
+use anyhow::anyhow;
+use chrono::DateTime;
+
+/// Maximum age of the events GA4 accepts to backdate, in microseconds.
+pub(crate) const MAX_EVENT_AGE_MICROS: i64 = 72 * 3600 * 1_000_000;
+
+/// Minimum delay of the events sent with a queue time, in microseconds: shorter delays are the
+/// usual processing time.
+pub(crate) const MIN_QUEUE_TIME_MICROS: i64 = 1_000_000;
+
+/// What to do with events older than 72 hours, from the `stale_event_action` setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum StaleEventAction {
+    /// Send them at the processing time, with the `stale_event=1` event parameter
+    #[default]
+    Flag,
+    /// Don't send them: the component returns an error with the age of the event
+    Reject,
+}
+
+impl StaleEventAction {
+    pub(crate) fn parse(action: &str) -> anyhow::Result<Self> {
+        match action.trim() {
+            "" | "flag" => Ok(StaleEventAction::Flag),
+            "reject" => Ok(StaleEventAction::Reject),
+            other => Err(anyhow!("Invalid stale_event_action: {other}")),
+        }
+    }
+}
+
+/// Delay of an event, when it's sent.
+#[derive(Debug, PartialEq)]
+pub(crate) enum EventDelay {
+    /// Sent right away, or at a time in the future (clock skew)
+    None,
+    /// Queue time, in milliseconds
+    Queued(i64),
+    /// Older than 72 hours, with the time of the event. ex: 2025-01-01T00:00:00Z
+    Stale(String),
+}
+
+/// Delay between an event (`timestamp_micros`, or `timestamp` in seconds when missing) and the
+/// time it's processed (`now_micros`).
+pub(crate) fn event_delay(timestamp: i64, timestamp_micros: i64, now_micros: i64) -> EventDelay {
+    let event_micros = if timestamp_micros > 0 {
+        timestamp_micros
+    } else {
+        timestamp.saturating_mul(1_000_000)
+    };
+    let delay = now_micros.saturating_sub(event_micros);
+    if delay > MAX_EVENT_AGE_MICROS {
+        let time = DateTime::from_timestamp_micros(event_micros)
+            .map(|time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_else(|| event_micros.to_string());
+        EventDelay::Stale(time)
+    } else if delay >= MIN_QUEUE_TIME_MICROS {
+        EventDelay::Queued(delay / 1000)
+    } else {
+        EventDelay::None
+    }
+}
+
+/// Current time, in microseconds.
+#[cfg(not(test))]
+pub(crate) fn now_micros() -> i64 {
+    chrono::Utc::now().timestamp_micros()
+}
+
+#[cfg(test)]
+thread_local! {
+    static NOW_MICROS: std::cell::Cell<Option<i64>> = const { std::cell::Cell::new(None) };
+}
+
+/// Current time, in microseconds: the time set by the test running on this thread, if any.
+#[cfg(test)]
+pub(crate) fn now_micros() -> i64 {
+    NOW_MICROS
+        .get()
+        .unwrap_or_else(|| chrono::Utc::now().timestamp_micros())
+}
+
+/// Set the current time of the tests running on this thread, in microseconds.
+#[cfg(test)]
+pub(crate) fn set_now_micros(now: i64) {
+    NOW_MICROS.set(Some(now));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const NOW: i64 = 1_735_689_600_000_000;
+
+    #[test]
+    fn delays() {
+        assert_eq!(event_delay(0, NOW, NOW), EventDelay::None);
+        assert_eq!(event_delay(0, NOW - 999, NOW), EventDelay::None);
+        assert_eq!(event_delay(0, NOW - 1000, NOW), EventDelay::None);
+        assert_eq!(event_delay(0, NOW + 5_000_000, NOW), EventDelay::None);
+        assert_eq!(
+            event_delay(0, NOW - 3_500_400, NOW),
+            EventDelay::Queued(3500)
+        );
+        assert_eq!(
+            event_delay(1_735_689_000, 0, NOW),
+            EventDelay::Queued(600_000)
+        );
+        assert_eq!(
+            event_delay(0, NOW - MAX_EVENT_AGE_MICROS, NOW),
+            EventDelay::Queued(259_200_000)
+        );
+    }
+
+    #[test]
+    fn queue_time_threshold() {
+        assert_eq!(event_delay(0, NOW - 999_999, NOW), EventDelay::None);
+        assert_eq!(
+            event_delay(0, NOW - MIN_QUEUE_TIME_MICROS, NOW),
+            EventDelay::Queued(1000)
+        );
+        assert_eq!(event_delay(1_735_689_600, 0, NOW), EventDelay::None);
+        assert_eq!(event_delay(1_735_689_599, 0, NOW), EventDelay::Queued(1000));
+    }
+
+    #[test]
+    fn stale_events() {
+        assert_eq!(
+            event_delay(0, NOW - MAX_EVENT_AGE_MICROS - 1, NOW),
+            EventDelay::Stale("2024-12-28T23:59:59Z".to_string())
+        );
+        assert_eq!(
+            event_delay(123, 0, NOW),
+            EventDelay::Stale("1970-01-01T00:02:03Z".to_string())
+        );
+    }
+
+    #[test]
+    fn actions() {
+        assert_eq!(StaleEventAction::parse("").unwrap(), StaleEventAction::Flag);
+        assert_eq!(
+            StaleEventAction::parse("reject").unwrap(),
+            StaleEventAction::Reject
+        );
+        assert_eq!(
+            StaleEventAction::parse("drop").unwrap_err().to_string(),
+            "Invalid stale_event_action: drop"
+        );
+    }
+}
@@ -7,7 +7,19 @@ use rand::{Rng, SeedableRng};
 use serde::Serialize;
 use std::collections::HashMap;
 
-use crate::exports::edgee::components::data_collection::{Consent, Dict, Event};
+use crate::bot::{is_bot, is_missing_client_hints, BotFiltering, BOT_TRAFFIC_TYPE};
+use crate::event_time::{event_delay, now_micros, EventDelay, StaleEventAction};
+use crate::exports::edgee::components::data_collection::{Consent, Event};
+use crate::linker::{find_linker, read_linker};
+use crate::locale::normalize_locale;
+use crate::page::{is_internal_referrer, ReferralExclusion};
+use crate::properties::apply_user_properties;
+use crate::sampling::is_sampled_in;
+use crate::screen::screen_resolution;
+use crate::settings::Settings;
+use crate::traffic::internal_traffic_type;
+use crate::user_agent::{normalize_mobile, parse_user_agent};
+use crate::user_id::user_identity;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ConsentMapping {
@@ -76,12 +88,15 @@ pub(crate) struct GaPayload {
     /// Browser screen resolution in format width x height. Ex: 1512x982 @javascript: (window.screen ? window.screen.width : 0) + "x" + (window.screen ? window.screen.height : 0)
     #[serde(rename = "sr", skip_serializing_if = "Option::is_none")]
     screen_resolution: Option<String>,
+    /// Browser viewport size in format width x height. Ex: 1280x720 @javascript: window.innerWidth + "x" + window.innerHeight
+    #[serde(rename = "vp", skip_serializing_if = "Option::is_none")]
+    pub viewport_size: Option<String>,
     /// Browser active locale. ex: fr-fr @javascript: (navigator.language || navigator.userLanguage || navigator.browserLanguage || navigator.systemLanguage || "en").toLowerCase()
     #[serde(rename = "ul")]
     user_language: String,
     /// Current Document Hostname. ex: www.edgee.dev
     #[serde(rename = "dh", skip_serializing_if = "Option::is_none")]
-    document_hostname: Option<String>,
+    pub document_hostname: Option<String>,
     /// Google Analytics Client Id. ex: 944266522.1681654733 @javascript: ('; ' + document.cookie).split('; _ga=').pop().split(';').shift().match(/GA1\.[0-9]{1}\.(.+)/)[1]
     #[serde(rename = "cid")]
     client_id: String,
@@ -120,10 +135,10 @@ pub(crate) struct GaPayload {
 
     // Shared
     /// Actual page's Pathname. It does not include the hostname, queryString or fragment. ex: /bonjour @javascript: document.location.pathname
-    #[serde(rename = "dl")]
+    #[serde(rename = "dl", skip_serializing_if = "String::is_empty")]
     pub document_location: String,
     /// Actual page's Title. Ex: GA4 standard @javascript: document.title
-    #[serde(rename = "dt")]
+    #[serde(rename = "dt", skip_serializing_if = "String::is_empty")]
     pub document_title: String,
     /// Actual page's Referrer. https://www.edgee.dev/ga-standard.html @javascript: document.referrer
     #[serde(rename = "dr", skip_serializing_if = "Option::is_none")]
@@ -145,7 +160,7 @@ pub(crate) struct GaPayload {
     ignore_referrer: Option<String>,
     /// Traffic Type. Ex: 1
     #[serde(rename = "tt", skip_serializing_if = "Option::is_none")]
-    traffic_type: Option<String>,
+    pub traffic_type: Option<String>,
     /// Will be set to 1 is the current page has a linker and this last one is valid. Ex: 1
     #[serde(rename = "_glv", skip_serializing_if = "Option::is_none")]
     is_google_linker_valid: Option<String>,
@@ -175,7 +190,7 @@ pub(crate) struct GaPayload {
     campaign_marketing_tactic: Option<String>,
     /// Random number used to dedupe gclid. Ex: 342342343
     #[serde(rename = "_rnd", skip_serializing_if = "Option::is_none")]
-    gclid_deduper: Option<String>,
+    pub gclid_deduper: Option<String>,
 
     // Event Parameters
     /// Current Event Name. Limits, 40 characters name length, 100 characters value length, 500 distinct event names by instance. Ex: page_view
@@ -184,6 +199,9 @@ pub(crate) struct GaPayload {
     /// It's the total engagement time in milliseconds since the last event. The engagement time is measured only when the current page is visible and active ( ie: the browser window/tab must be active and visible ), for this GA4 uses the window.events: focus, blur, pageshow, pagehide and the document:visibilitychange, these will determine when the timer starts and pauses. Ex: 1234
     #[serde(rename = "_et", skip_serializing_if = "Option::is_none")]
     pub engagement_time: Option<String>,
+    /// Queue time: delay, in milliseconds, between the event and the moment the hit is sent. Ex: 3500
+    #[serde(rename = "qt", skip_serializing_if = "Option::is_none")]
+    queue_time: Option<String>,
 
     // Dynamic parameter handling is more complex in Rust and might require a custom deserializer
     /// Defines a parameter for the current Event with ep.* semantic. Ex: ep.page_type: checkout
@@ -319,7 +337,7 @@ pub(crate) struct GaPayload {
 }
 
 /// Product (item). Converted into a GA4 string, it contains an item details and all it's params.
-/// Example Value: &pr1=id123456~nmTshirtbrThyngster~camen~c2shirts~pr129.99~k0currency~v0JPY~k1stock~v1yes
+/// Example Value: &pr1=id123456~nmTshirtbrThyngster~camen~c2shirts~pr129.99~cuJPY~k0stock~v0yes
 #[derive(Serialize, Debug, Default)]
 pub struct Product {
     // items parameters (An event can only hold up to 200 items details. Any items above that limit will be removed from the payload)
@@ -358,39 +376,76 @@ pub struct Product {
     #[serde(rename = "lo", skip_serializing_if = "Option::is_none")]
     pub location_id: Option<String>, // ChIJIQBpAG2ahYAR_6128GcTUEo
 
+    #[serde(rename = "pi", skip_serializing_if = "Option::is_none")]
+    pub promotion_id: Option<String>, // P_12345
+    #[serde(rename = "pn", skip_serializing_if = "Option::is_none")]
+    pub promotion_name: Option<String>, // Summer Sale
+    #[serde(rename = "cn", skip_serializing_if = "Option::is_none")]
+    pub creative_name: Option<String>, // summer_banner2
+    #[serde(rename = "cs", skip_serializing_if = "Option::is_none")]
+    pub creative_slot: Option<String>, // featured_app_1
+
     #[serde(rename = "pr", skip_serializing_if = "Option::is_none")]
     pub price: Option<String>, // 19.99
     #[serde(rename = "qt", skip_serializing_if = "Option::is_none")]
     pub quantity: Option<String>, // 1
 
+    // currency of the item, only sent when it differs from the event currency and has no
+    // exchange rate, the other items are converted (see currency.rs)
+    #[serde(skip)]
+    pub currency: Option<String>, // EUR
+
     // custom parameters
     // each parameter is a key/value pair
     // ex: in_stock: true, color: green
     // will be converted into a k0in_stock~v0true~k1color~v1green
     #[serde(skip_serializing_if = "Option::is_none")]
     pub custom_parameters: Option<Vec<(String, String)>>,
+
+    // numeric custom parameters, for item-scoped custom metrics
+    // ex: weight: 180 will be converted into a k0weight~vn0180
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_metrics: Option<Vec<(String, String)>>,
+}
+
+impl Product {
+    /// Add an item-scoped custom parameter.
+    /// GA4 parameter names only contain letters, digits and underscores and start with a letter:
+    /// other characters are replaced by `_` and names that can't be fixed are dropped.
+    pub fn add_custom_parameter(&mut self, key: &str, value: String) {
+        let Some(key) = sanitize_parameter_name(key) else {
+            return;
+        };
+        self.custom_parameters
+            .get_or_insert_with(Vec::new)
+            .push((key, value));
+    }
+}
+
+/// Sanitize a custom parameter name, see https://support.google.com/analytics/answer/13316687
+fn sanitize_parameter_name(name: &str) -> Option<String> {
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(40)
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        Some(name)
+    } else {
+        None
+    }
 }
 
 impl GaPayload {
     pub(crate) fn new(
         edgee_event: &Event,
-        settings: Dict,
+        settings: &Settings,
         event_name: String,
     ) -> anyhow::Result<Self> {
-        let cred: HashMap<String, String> = settings
-            .iter()
-            .map(|(key, value)| (key.to_string(), value.to_string()))
-            .collect();
-
-        let measurement_id = match cred.get("ga_measurement_id") {
-            Some(v) => v,
-            None => return Err(anyhow!("Missing GA Measurement ID")),
-        }
-        .to_string();
-
         let mut ga = GaPayload {
             protocol_version: "2".to_string(),
-            tracking_id: measurement_id,
+            tracking_id: settings.measurement_id.clone(),
             event_name,
             random_page_load_hash: random_page_load_hash(),
             external_event: Some("1".to_string()),
@@ -404,20 +459,34 @@ impl GaPayload {
         if !edgee_event.context.page.url.is_empty() {
             ga.document_location = edgee_event.context.page.url.clone();
         }
-        if !edgee_event.context.page.referrer.is_empty() {
-            // if edgee_event.context.page.referrer does not starts with edgee_event.context.page.url, set it
-            if !edgee_event
-                .context
-                .page
-                .referrer
-                .starts_with(&edgee_event.context.page.url)
-            {
-                ga.document_referrer = Some(edgee_event.context.page.referrer.clone());
+        ga.set_referrer(
+            &edgee_event.context.page.referrer,
+            &edgee_event.context.page.url,
+            settings,
+        );
+
+        ga.traffic_type = internal_traffic_type(&edgee_event.context.client, settings);
+
+        // bot traffic
+        let client = &edgee_event.context.client;
+        if settings.bot_filtering != BotFiltering::Off
+            && (is_bot(client, &settings.bot_user_agents)
+                || settings.bot_require_client_hints && is_missing_client_hints(client))
+        {
+            match settings.bot_filtering {
+                BotFiltering::Drop => return Err(anyhow!("Bot traffic is dropped")),
+                BotFiltering::Flag => {
+                    ga.event_parameter_string
+                        .get_or_insert_with(HashMap::new)
+                        .insert("bot".to_string(), "1".to_string());
+                }
+                BotFiltering::TrafficType => ga.traffic_type = Some(BOT_TRAFFIC_TYPE.to_string()),
+                BotFiltering::Off => {}
             }
         }
 
         // Apply configurable consent mapping
-        let consent_mapping = get_consent_mapping(&edgee_event.consent, &cred);
+        let consent_mapping = get_consent_mapping(&edgee_event.consent, &settings.values);
         consent_mapping.apply_to_ga_payload(&mut ga);
 
         // forge the typical ga ClientId
@@ -434,11 +503,8 @@ impl GaPayload {
 
         ga.hit_counter = "1".to_string();
 
-        if edgee_event.context.client.locale.is_empty() {
-            ga.user_language = "en".to_string();
-        } else {
-            ga.user_language = edgee_event.context.client.locale.clone();
-        }
+        ga.user_language = normalize_locale(&edgee_event.context.client.locale)
+            .unwrap_or_else(|| settings.default_language.clone());
         if !edgee_event
             .context
             .client
@@ -453,9 +519,6 @@ impl GaPayload {
                     .clone(),
             );
         }
-        if !edgee_event.context.client.user_agent_mobile.is_empty() {
-            ga.user_agent_mobile = Some(edgee_event.context.client.user_agent_mobile.clone());
-        }
         if !edgee_event.context.client.os_name.is_empty() {
             ga.user_agent_platform = Some(edgee_event.context.client.os_name.clone());
         }
@@ -478,51 +541,50 @@ impl GaPayload {
             ga.user_agent_model = Some(edgee_event.context.client.user_agent_model.clone());
         }
 
-        if edgee_event.context.client.screen_width.is_positive()
-            && edgee_event.context.client.screen_height.is_positive()
+        // client hints derived from the User-Agent, when the browser didn't send them
+        let user_agent = parse_user_agent(&edgee_event.context.client.user_agent);
+        if ga.user_agent_full_version_list.is_none() {
+            ga.user_agent_full_version_list = user_agent.full_version_list;
+        }
+        if ga.user_agent_platform.is_none() {
+            ga.user_agent_platform = user_agent.platform.clone();
+        }
+        if ga.user_agent_platform_version.is_none()
+            && ga.user_agent_platform.as_ref() == user_agent.platform.as_ref()
         {
-            ga.screen_resolution = Some(format!(
-                "{:?}x{:?}",
-                edgee_event.context.client.screen_width.clone(),
-                edgee_event.context.client.screen_height.clone()
-            ));
+            ga.user_agent_platform_version = user_agent.platform_version;
+        }
+        ga.user_agent_mobile = normalize_mobile(&edgee_event.context.client.user_agent_mobile)
+            .map(String::from)
+            .or_else(|| {
+                (!edgee_event.context.client.user_agent.is_empty())
+                    .then(|| if user_agent.mobile { "1" } else { "0" }.to_string())
+            });
+        if !edgee_event.context.client.user_agent.is_empty() {
+            ga.user_agent_wow64 = Some(if user_agent.wow64 { "1" } else { "0" }.to_string());
         }
 
+        ga.screen_resolution = screen_resolution(
+            edgee_event.context.client.screen_width,
+            edgee_event.context.client.screen_height,
+            edgee_event.context.client.screen_density,
+            settings.screen_resolution_unit,
+        );
+
         // user
-        let mut user_property_string: HashMap<String, String> = HashMap::new();
-        let mut user_property_number: HashMap<String, f64> = HashMap::new();
-        if !edgee_event.context.user.anonymous_id.is_empty() {
-            ga.user_id = Some(edgee_event.context.user.anonymous_id.clone());
-        }
-        if !edgee_event.context.user.user_id.is_empty() {
-            ga.user_id = Some(edgee_event.context.user.user_id.clone());
-            if !edgee_event.context.user.anonymous_id.is_empty() {
-                user_property_string.insert(
-                    "anonymous_id".to_string(),
-                    edgee_event.context.user.anonymous_id.clone(),
-                );
-            }
+        let (user_id, anonymous_id) = user_identity(
+            &edgee_event.context.user.user_id,
+            &edgee_event.context.user.anonymous_id,
+            settings,
+        );
+        ga.user_id = user_id;
+        if let Some(anonymous_id) = anonymous_id {
+            ga.user_property_string =
+                Some(HashMap::from([("anonymous_id".to_string(), anonymous_id)]));
         }
 
         // user properties
-        if !edgee_event.context.user.properties.is_empty() {
-            for (key, value) in edgee_event.context.user.properties.clone().iter() {
-                // if key has a space, replace by a _
-                let key = key.replace(" ", "_");
-                if value.parse::<f64>().is_ok() {
-                    user_property_number.insert(key, value.parse().unwrap());
-                } else {
-                    user_property_string.insert(key, value.clone());
-                }
-            }
-        }
-
-        if !user_property_string.is_empty() {
-            ga.user_property_string = Some(user_property_string);
-        }
-        if !user_property_number.is_empty() {
-            ga.user_property_number = Some(user_property_number);
-        }
+        apply_user_properties(&mut ga, &edgee_event.context.user.properties);
 
         // geo ip
         if !edgee_event.context.client.country_code.is_empty() {
@@ -591,8 +653,91 @@ impl GaPayload {
             ga.session_engagement = Some(String::from("1"));
         }
 
+        // cross-domain linker: keep the client ID and session of the site the user comes from
+        if settings.accept_incoming_linker {
+            let page = &edgee_event.context.page;
+            if let Some(linker) = find_linker(&page.search, &page.url).and_then(|linker| {
+                read_linker(
+                    linker,
+                    &settings.measurement_id,
+                    &edgee_event.context.client,
+                    edgee_event.timestamp,
+                )
+            }) {
+                // neither a new visitor nor, with a session, a new session
+                ga.client_id = linker.client_id;
+                ga.first_visit = None;
+                ga.new_session_id = None;
+                if let Some(session) = linker.session {
+                    ga.session_id = Some(session.session_id);
+                    if session.session_count.is_some() {
+                        ga.session_count = session.session_count;
+                    }
+                    ga.session_start = None;
+                    ga.session_engagement = Some(String::from("1"));
+                }
+                ga.is_google_linker_valid = Some(String::from("1"));
+            }
+        }
+
+        // event time: delayed hits are sent with their queue time, GA4 doesn't accept stale ones
+        match event_delay(
+            edgee_event.timestamp,
+            edgee_event.timestamp_micros,
+            now_micros(),
+        ) {
+            EventDelay::None => {}
+            EventDelay::Queued(delay) => ga.queue_time = Some(delay.to_string()),
+            EventDelay::Stale(time) => match settings.stale_event_action {
+                StaleEventAction::Reject => {
+                    return Err(anyhow!("Event is older than 72 hours: {time}"))
+                }
+                StaleEventAction::Flag => {
+                    ga.event_parameter_string
+                        .get_or_insert_with(HashMap::new)
+                        .insert("stale_event".to_string(), "1".to_string());
+                }
+            },
+        }
+
+        // sampling, once the client ID is known: the sample rate is sent for downstream weighting
+        if let Some((_, rate)) = settings
+            .sampling_rates
+            .iter()
+            .find(|(name, _)| *name == ga.event_name)
+        {
+            if !is_sampled_in(&ga.client_id, *rate) {
+                return Err(anyhow!("Event is sampled out: {}", ga.event_name));
+            }
+            ga.event_parameter_number
+                .get_or_insert_with(HashMap::new)
+                .insert("sample_rate".to_string(), *rate);
+        }
+
         Ok(ga)
     }
+
+    /// Set the referrer of the page. A referrer starting with the page URL (reload) is not sent;
+    /// an internal referrer (same site, excluded or payment domain) is ignored or dropped.
+    pub(crate) fn set_referrer(&mut self, referrer: &str, page_url: &str, settings: &Settings) {
+        self.document_referrer = None;
+        self.ignore_referrer = None;
+        if referrer.is_empty() || referrer.starts_with(page_url) {
+            return;
+        }
+        if is_internal_referrer(
+            referrer,
+            page_url,
+            &settings.referral_exclusion_domains,
+            &settings.payment_domains,
+        ) {
+            match settings.referral_exclusion_action {
+                ReferralExclusion::IgnoreReferrer => self.ignore_referrer = Some("1".to_string()),
+                ReferralExclusion::DropReferrer => return,
+            }
+        }
+        self.document_referrer = Some(referrer.to_string());
+    }
 }
 
 fn random_page_load_hash() -> String {
@@ -714,6 +859,33 @@ mod tests {
         assert_eq!(result.len(), 9);
     }
 
+    #[test]
+    fn add_custom_parameter_sanitizes_names() {
+        let mut product = Product::default();
+        product.add_custom_parameter("in stock", "yes".to_string());
+        product.add_custom_parameter("size~k1color", "XL".to_string());
+        product.add_custom_parameter("été", "1".to_string());
+        product.add_custom_parameter("2nd_choice", "no".to_string());
+        product.add_custom_parameter("~", "no".to_string());
+
+        assert_eq!(
+            product.custom_parameters,
+            Some(vec![
+                ("in_stock".to_string(), "yes".to_string()),
+                ("size_k1color".to_string(), "XL".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn add_custom_parameter_truncates_long_names() {
+        let mut product = Product::default();
+        product.add_custom_parameter(&"a".repeat(50), "1".to_string());
+
+        let (key, _) = &product.custom_parameters.unwrap()[0];
+        assert_eq!(key.len(), 40);
+    }
+
     #[test]
     fn random_page_load_hash_length() {
         let result = random_page_load_hash();
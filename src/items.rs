@@ -0,0 +1,662 @@
+//! Conversion of Edgee products into GA4 items.
+use crate::currency::normalize_currency;
+use crate::exports::edgee::components::data_collection::Dict;
+use crate::ga_payload::{GaPayload, Product};
+
+/// Event parameters describing the context of all the items of an event (the list they were
+/// displayed in, the promotion they come from...). They are sent as `ep.*` and also copied into
+/// every item that doesn't define its own value, like gtag.js does.
+pub(crate) const ITEM_CONTEXT_PARAMETERS: [&str; 7] = [
+    "item_list_id",
+    "item_list_name",
+    "promotion_id",
+    "promotion_name",
+    "creative_name",
+    "creative_slot",
+    "affiliation",
+];
+
+/// E-commerce events whose `value` can be computed from their items.
+pub(crate) const VALUE_EVENTS: [&str; 10] = [
+    "add_payment_info",
+    "add_shipping_info",
+    "add_to_cart",
+    "add_to_wishlist",
+    "begin_checkout",
+    "purchase",
+    "refund",
+    "remove_from_cart",
+    "view_cart",
+    "view_item",
+];
+
+/// Product keys of other specs (GA4 `item_*` names, Segment, Shopify, UA enhanced e-commerce)
+/// and the Edgee product key they stand for.
+const PRODUCT_KEY_ALIASES: [(&str, &str); 21] = [
+    // GA4
+    ("item_id", "sku"),
+    ("item_name", "name"),
+    ("item_brand", "brand"),
+    ("item_category", "category"),
+    ("item_category2", "category2"),
+    ("item_category3", "category3"),
+    ("item_category4", "category4"),
+    ("item_category5", "category5"),
+    ("item_variant", "variant"),
+    ("item_list_id", "list_id"),
+    ("item_list_name", "list_name"),
+    // Segment
+    ("product_id", "sku"),
+    ("url", "item_url"),
+    ("image_url", "item_image_url"),
+    // Shopify
+    ("title", "name"),
+    ("vendor", "brand"),
+    ("product_type", "category"),
+    ("variant_title", "variant"),
+    // UA enhanced e-commerce
+    ("id", "sku"),
+    ("list", "list_name"),
+    ("position", "index"),
+];
+
+/// Event-level item context, read from the track properties.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct ItemContext {
+    pub list_id: Option<String>,
+    pub list_name: Option<String>,
+    pub promotion_id: Option<String>,
+    pub promotion_name: Option<String>,
+    pub creative_name: Option<String>,
+    pub creative_slot: Option<String>,
+    pub affiliation: Option<String>,
+}
+
+impl ItemContext {
+    pub(crate) fn from_properties(properties: &Dict) -> Self {
+        let mut context = ItemContext::default();
+        for (key, value) in properties.iter() {
+            if value.is_empty() {
+                continue;
+            }
+            let field = match key.replace(" ", "_").as_str() {
+                "item_list_id" => &mut context.list_id,
+                "item_list_name" => &mut context.list_name,
+                "promotion_id" => &mut context.promotion_id,
+                "promotion_name" => &mut context.promotion_name,
+                "creative_name" => &mut context.creative_name,
+                "creative_slot" => &mut context.creative_slot,
+                "affiliation" => &mut context.affiliation,
+                _ => continue,
+            };
+            *field = Some(value.clone());
+        }
+        context
+    }
+}
+
+/// Convert the products of a track event into GA4 items.
+/// `aliases` are user-defined product keys, checked before the built-in ones.
+pub(crate) fn build_items(
+    products: &[Dict],
+    context: &ItemContext,
+    aliases: &[(String, String)],
+) -> Vec<Product> {
+    products
+        .iter()
+        .enumerate()
+        .map(|(position, product)| {
+            let product = resolve_aliases(flatten_product(product), aliases);
+            let mut item = product_from_dict(&product);
+            inherit_context(&mut item, context, position);
+            item
+        })
+        .collect()
+}
+
+/// Expand structured product values into flat keys, in a predictable way:
+/// - dotted keys and JSON objects are flattened with `_`. ex: `dimensions.color` or
+///   `dimensions = {"color": "red"}` -> `dimensions_color`
+/// - a `category` or `categories` JSON array fills `category` to `category5`
+/// - other JSON arrays are flattened with the position of each value. ex: `sizes_0`
+///
+/// Values that aren't a JSON object or array are kept as they are.
+fn flatten_product(product: &Dict) -> Dict {
+    let mut flat = Vec::new();
+    for (key, value) in product.iter() {
+        let path = key.replace(" ", "_").split('.').map(String::from).collect();
+        match parse_structured(value) {
+            Some(json) => flatten_json(path, &json, &mut flat),
+            None => flat.push((flat_key(&path), value.clone())),
+        }
+    }
+    flat
+}
+
+fn parse_structured(value: &str) -> Option<serde_json::Value> {
+    let value = value.trim();
+    if !value.starts_with('[') && !value.starts_with('{') {
+        return None;
+    }
+    serde_json::from_str(value).ok()
+}
+
+fn flatten_json(path: Vec<String>, value: &serde_json::Value, flat: &mut Dict) {
+    use serde_json::Value;
+    let mut child = |key: String, value: &Value| {
+        let mut path = path.clone();
+        path.push(key);
+        flatten_json(path, value, flat);
+    };
+    match value {
+        Value::Null => {}
+        Value::Object(fields) => fields.iter().for_each(|(k, v)| child(k.clone(), v)),
+        Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .for_each(|(i, v)| child(i.to_string(), v)),
+        Value::String(value) => flat.push((flat_key(&path), value.clone())),
+        value => flat.push((flat_key(&path), value.to_string())),
+    }
+}
+
+fn flat_key(path: &[String]) -> String {
+    if let [name, position] = path {
+        if name == "category" || name == "categories" {
+            match position.parse::<usize>() {
+                Ok(0) => return "category".to_string(),
+                Ok(position @ 1..=4) => return format!("category{}", position + 1),
+                _ => {}
+            }
+        }
+    }
+    path.join("_")
+}
+
+/// Parse the `product_key_aliases` setting: a comma separated list of `ALIAS:KEY`.
+/// ex: "sku_code:sku,prod_title:name"
+pub(crate) fn parse_key_aliases(aliases: &str) -> Vec<(String, String)> {
+    aliases
+        .split(',')
+        .filter_map(|entry| {
+            let (alias, key) = entry.split_once(':')?;
+            let (alias, key) = (alias.trim().replace(" ", "_"), key.trim().replace(" ", "_"));
+            (!alias.is_empty() && !key.is_empty()).then_some((alias, key))
+        })
+        .collect()
+}
+
+/// Rename the aliased keys of a product. When a product has both a key and one of its aliases, the
+/// key wins: aliased entries are moved first, so that the key overrides them.
+fn resolve_aliases(product: Dict, aliases: &[(String, String)]) -> Dict {
+    let (aliased, canonical): (Vec<_>, Vec<_>) = product
+        .into_iter()
+        .map(|(key, value)| {
+            let alias = aliases
+                .iter()
+                .map(|(alias, key)| (alias.as_str(), key.as_str()))
+                .chain(PRODUCT_KEY_ALIASES)
+                .find(|(alias, _)| *alias == key);
+            match alias {
+                Some((_, key)) => (true, (key.to_string(), value)),
+                None => (key == "price_cents", (key, value)),
+            }
+        })
+        .partition(|(aliased, _)| *aliased);
+    aliased
+        .into_iter()
+        .chain(canonical)
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+fn product_from_dict(product: &Dict) -> Product {
+    let mut p = Product::default();
+
+    for (key, value) in product.iter() {
+        let key = key.replace(" ", "_");
+        match key.as_str() {
+            "sku" => p.sku = Some(value.clone()),
+            "name" => p.name = Some(value.clone()),
+            "affiliation" => p.affiliation = Some(value.clone()),
+            "coupon" => p.coupon = Some(value.clone()),
+            "index" => p.index = Some(value.clone()),
+            "brand" => p.brand = Some(value.clone()),
+            "category" => p.category = Some(value.clone()),
+            "category2" => p.category2 = Some(value.clone()),
+            "category3" => p.category3 = Some(value.clone()),
+            "category4" => p.category4 = Some(value.clone()),
+            "category5" => p.category5 = Some(value.clone()),
+            "list_id" => p.list_id = Some(value.clone()),
+            "list_name" => p.list_name = Some(value.clone()),
+            "variant" => p.variant = Some(value.clone()),
+            "location_id" => p.location_id = Some(value.clone()),
+            "promotion_id" => p.promotion_id = Some(value.clone()),
+            "promotion_name" => p.promotion_name = Some(value.clone()),
+            "creative_name" => p.creative_name = Some(value.clone()),
+            "creative_slot" => p.creative_slot = Some(value.clone()),
+            "discount" => p.discount = parse_amount(value).map(format_number),
+            "price" => p.price = parse_amount(value).map(format_number),
+            "price_cents" => {
+                p.price = parse_amount(value).map(|cents| format_number(cents / 100.0))
+            }
+            "quantity" => p.quantity = parse_quantity(value).map(|q| q.to_string()),
+            "currency" => p.currency = normalize_currency(value),
+            _ => p.add_custom_parameter(&key, value.clone()),
+        }
+    }
+
+    p
+}
+
+/// Refunds report the refunded units of each item as a positive quantity. Back-offices often
+/// record returned units as negative quantities (ex: "-2"); items without any refunded unit are
+/// not part of the refund. A negative refund `value` is made positive too.
+pub(crate) fn prepare_refund(ga: &mut GaPayload, items: &mut Vec<Product>) {
+    items.retain_mut(|item| {
+        match item.quantity.as_deref().and_then(parse_quantity) {
+            Some(0) => return false,
+            Some(quantity) => item.quantity = Some(quantity.abs().to_string()),
+            None => {}
+        }
+        true
+    });
+
+    if let Some(value) = ga
+        .event_parameter_number
+        .as_mut()
+        .and_then(|params| params.get_mut("value"))
+    {
+        *value = value.abs();
+    }
+}
+
+/// Put the custom parameters of every item in a deterministic order (sorted by name) and keep
+/// the first `limit` ones. With `custom_metrics`, parameters with a numeric value are sent as
+/// item-scoped custom metrics instead.
+pub(crate) fn finalize_custom_parameters(
+    items: &mut [Product],
+    limit: usize,
+    custom_metrics: bool,
+) {
+    for item in items.iter_mut() {
+        let Some(mut params) = item.custom_parameters.take() else {
+            continue;
+        };
+        params.sort_by(|(a, _), (b, _)| a.cmp(b));
+        params.truncate(limit);
+
+        let (metrics, params): (Vec<_>, Vec<_>) = params
+            .into_iter()
+            .partition(|(_, value)| custom_metrics && parse_amount(value).is_some());
+        item.custom_parameters = Some(params).filter(|p| !p.is_empty());
+        item.custom_metrics = Some(metrics).filter(|m| !m.is_empty());
+    }
+}
+
+/// Total value of the items: sum of `price * quantity - discount`, quantity defaults to 1.
+/// Items still in their own currency (no exchange rate) are left out.
+/// Returns None when no item has a price.
+pub(crate) fn items_value(items: &[Product]) -> Option<f64> {
+    let mut total = None;
+    for item in items.iter().filter(|item| item.currency.is_none()) {
+        let Some(price) = item.price.as_deref().and_then(parse_amount) else {
+            continue;
+        };
+        let quantity = item
+            .quantity
+            .as_deref()
+            .and_then(parse_quantity)
+            .unwrap_or(1);
+        let discount = item
+            .discount
+            .as_deref()
+            .and_then(parse_amount)
+            .unwrap_or(0.0);
+        *total.get_or_insert(0.0) += price * quantity as f64 - discount;
+    }
+    total.map(round_amount)
+}
+
+/// Parse a monetary amount. ex: "19.99"
+pub(crate) fn parse_amount(value: &str) -> Option<f64> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|amount| amount.is_finite())
+}
+
+/// Parse an item quantity, which must be a whole number. ex: "3" or "3.0"
+fn parse_quantity(value: &str) -> Option<i64> {
+    parse_amount(value)
+        .filter(|quantity| quantity.fract() == 0.0 && quantity.abs() < i64::MAX as f64)
+        .map(|quantity| quantity as i64)
+}
+
+/// Round away floating point noise (10.1 * 3 is 30.299999999999997).
+pub(crate) fn round_amount(amount: f64) -> f64 {
+    (amount * 1_000_000.0).round() / 1_000_000.0
+}
+
+fn format_number(amount: f64) -> String {
+    round_amount(amount).to_string()
+}
+
+/// Fill the item fields left empty with the event-level context. Items displayed in a list get
+/// their position in the products array as `index` when they don't have one.
+fn inherit_context(item: &mut Product, context: &ItemContext, position: usize) {
+    let inherited = [
+        (&mut item.list_id, &context.list_id),
+        (&mut item.list_name, &context.list_name),
+        (&mut item.promotion_id, &context.promotion_id),
+        (&mut item.promotion_name, &context.promotion_name),
+        (&mut item.creative_name, &context.creative_name),
+        (&mut item.creative_slot, &context.creative_slot),
+        (&mut item.affiliation, &context.affiliation),
+    ];
+    for (field, default) in inherited {
+        if field.is_none() {
+            field.clone_from(default);
+        }
+    }
+
+    if item.index.is_none() && (item.list_id.is_some() || item.list_name.is_some()) {
+        item.index = Some(position.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::dict;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    #[test]
+    fn item_context_from_properties() {
+        let context = ItemContext::from_properties(&dict(&[
+            ("item_list_id", "category_shoes"),
+            ("item list name", "Shoes"),
+            ("promotion_id", ""),
+            ("value", "10"),
+        ]));
+        assert_eq!(
+            context,
+            ItemContext {
+                list_id: Some("category_shoes".to_string()),
+                list_name: Some("Shoes".to_string()),
+                ..ItemContext::default()
+            }
+        );
+    }
+
+    #[test]
+    fn items_inherit_event_context() {
+        let context = ItemContext {
+            list_id: Some("category_shoes".to_string()),
+            list_name: Some("Shoes".to_string()),
+            promotion_name: Some("Summer Sale".to_string()),
+            affiliation: Some("Online Store".to_string()),
+            ..ItemContext::default()
+        };
+        let items = build_items(
+            &[
+                dict(&[("sku", "A")]),
+                dict(&[("sku", "B"), ("list_name", "Featured"), ("index", "7")]),
+                dict(&[("sku", "C"), ("affiliation", "Outlet")]),
+            ],
+            &context,
+            &[],
+        );
+
+        assert_eq!(items[0].list_id.as_deref(), Some("category_shoes"));
+        assert_eq!(items[0].list_name.as_deref(), Some("Shoes"));
+        assert_eq!(items[0].promotion_name.as_deref(), Some("Summer Sale"));
+        assert_eq!(items[0].index.as_deref(), Some("0"));
+        assert_eq!(items[1].list_name.as_deref(), Some("Featured"));
+        assert_eq!(items[1].index.as_deref(), Some("7"));
+        assert_eq!(items[2].index.as_deref(), Some("2"));
+        assert_eq!(items[2].affiliation.as_deref(), Some("Outlet"));
+        assert_eq!(items[1].affiliation.as_deref(), Some("Online Store"));
+    }
+
+    #[test]
+    fn prices_and_quantities_are_validated() {
+        let items = build_items(
+            &[
+                dict(&[("price", " 10.10 "), ("quantity", "3.0"), ("discount", "1")]),
+                dict(&[("price", "ten"), ("quantity", "2.5"), ("discount", "NaN")]),
+            ],
+            &ItemContext::default(),
+            &[],
+        );
+        assert_eq!(items[0].price.as_deref(), Some("10.1"));
+        assert_eq!(items[0].quantity.as_deref(), Some("3"));
+        assert_eq!(items[0].discount.as_deref(), Some("1"));
+        assert_eq!(items[1].price, None);
+        assert_eq!(items[1].quantity, None);
+        assert_eq!(items[1].discount, None);
+    }
+
+    #[test]
+    fn item_currency_is_normalized() {
+        let items = build_items(
+            &[
+                dict(&[("currency", "usd")]),
+                dict(&[("currency", "dollar")]),
+            ],
+            &ItemContext::default(),
+            &[],
+        );
+        assert_eq!(items[0].currency.as_deref(), Some("USD"));
+        assert_eq!(items[1].currency, None);
+        assert_eq!(items[1].custom_parameters, None);
+    }
+
+    #[test]
+    fn items_value_sums_prices() {
+        let items = build_items(
+            &[
+                dict(&[("price", "10.1"), ("quantity", "3")]),
+                dict(&[("price", "5"), ("discount", "0.5")]),
+                dict(&[("sku", "no price"), ("quantity", "4")]),
+            ],
+            &ItemContext::default(),
+            &[],
+        );
+        assert_eq!(items_value(&items), Some(34.8));
+    }
+
+    #[test]
+    fn items_value_without_prices() {
+        let items = build_items(&[dict(&[("sku", "A")])], &ItemContext::default(), &[]);
+        assert_eq!(items_value(&items), None);
+        assert_eq!(items_value(&[]), None);
+    }
+
+    #[test]
+    fn refunds_use_positive_quantities() {
+        let mut ga = GaPayload::default();
+        ga.event_parameter_number = Some(HashMap::from([("value".to_string(), -30.0)]));
+        let mut items = build_items(
+            &[
+                dict(&[("sku", "A"), ("price", "10"), ("quantity", "-2")]),
+                dict(&[("sku", "B"), ("price", "5"), ("quantity", "0")]),
+                dict(&[("sku", "C"), ("price", "7")]),
+            ],
+            &ItemContext::default(),
+            &[],
+        );
+        prepare_refund(&mut ga, &mut items);
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].quantity.as_deref(), Some("2"));
+        assert_eq!(items[1].sku.as_deref(), Some("C"));
+        assert_eq!(items[1].quantity, None);
+        assert_eq!(items_value(&items), Some(27.0));
+        assert_eq!(
+            ga.event_parameter_number,
+            Some(HashMap::from([("value".to_string(), 30.0)]))
+        );
+    }
+
+    #[test]
+    fn structured_products_are_flattened() {
+        let items = build_items(
+            &[dict(&[
+                ("sku", "A"),
+                (
+                    "categories",
+                    r#"["Apparel", "Men", "Shirts", "Short", "Cotton", "Sale"]"#,
+                ),
+                ("dimensions.color", "red"),
+                (
+                    "dimensions",
+                    r#"{"size": {"eu": 42, "us": null}, "fit": "slim"}"#,
+                ),
+                ("sizes", "[true, 1.5]"),
+                ("name", "[NEW] Shirt"),
+                ("price", "{not json"),
+            ])],
+            &ItemContext::default(),
+            &[],
+        );
+
+        assert_eq!(items[0].name.as_deref(), Some("[NEW] Shirt"));
+        assert_eq!(items[0].category.as_deref(), Some("Apparel"));
+        assert_eq!(items[0].category2.as_deref(), Some("Men"));
+        assert_eq!(items[0].category5.as_deref(), Some("Cotton"));
+        assert_eq!(items[0].price, None);
+        assert_eq!(
+            items[0].custom_parameters,
+            Some(vec![
+                ("categories_5".to_string(), "Sale".to_string()),
+                ("dimensions_color".to_string(), "red".to_string()),
+                ("dimensions_size_eu".to_string(), "42".to_string()),
+                ("dimensions_fit".to_string(), "slim".to_string()),
+                ("sizes_0".to_string(), "true".to_string()),
+                ("sizes_1".to_string(), "1.5".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn structured_values_set_item_fields() {
+        let items = build_items(
+            &[dict(&[
+                ("category", r#"["Shoes"]"#),
+                ("item", r#"{"price": 10}"#),
+            ])],
+            &ItemContext::default(),
+            &[],
+        );
+        assert_eq!(items[0].category.as_deref(), Some("Shoes"));
+        assert_eq!(
+            items[0].custom_parameters,
+            Some(vec![("item_price".to_string(), "10".to_string())])
+        );
+    }
+
+    #[test]
+    fn custom_parameters_are_sorted_and_limited() {
+        let mut items = build_items(
+            &[dict(&[
+                ("size", "M"),
+                ("weight", "180"),
+                ("color", "green"),
+                ("age", "2"),
+            ])],
+            &ItemContext::default(),
+            &[],
+        );
+        finalize_custom_parameters(&mut items, 3, false);
+        assert_eq!(
+            items[0].custom_parameters,
+            Some(vec![
+                ("age".to_string(), "2".to_string()),
+                ("color".to_string(), "green".to_string()),
+                ("size".to_string(), "M".to_string()),
+            ])
+        );
+        assert_eq!(items[0].custom_metrics, None);
+    }
+
+    #[test]
+    fn numeric_custom_parameters_are_metrics() {
+        let mut items = build_items(
+            &[dict(&[("weight", "180.5"), ("color", "green")])],
+            &ItemContext::default(),
+            &[],
+        );
+        finalize_custom_parameters(&mut items, 27, true);
+        assert_eq!(
+            items[0].custom_parameters,
+            Some(vec![("color".to_string(), "green".to_string())])
+        );
+        assert_eq!(
+            items[0].custom_metrics,
+            Some(vec![("weight".to_string(), "180.5".to_string())])
+        );
+    }
+
+    #[test]
+    fn product_key_aliases() {
+        let items = build_items(
+            &[
+                dict(&[
+                    ("item_id", "A"),
+                    ("item_name", "Tee"),
+                    ("item_category2", "Adult"),
+                    ("vendor", "Google"),
+                    ("position", "3"),
+                    ("price_cents", "1999"),
+                ]),
+                dict(&[
+                    ("sku", "B"),
+                    ("product_id", "42"),
+                    ("price", "5"),
+                    ("price_cents", "700"),
+                ]),
+            ],
+            &ItemContext::default(),
+            &[],
+        );
+        assert_eq!(items[0].sku.as_deref(), Some("A"));
+        assert_eq!(items[0].name.as_deref(), Some("Tee"));
+        assert_eq!(items[0].category2.as_deref(), Some("Adult"));
+        assert_eq!(items[0].brand.as_deref(), Some("Google"));
+        assert_eq!(items[0].index.as_deref(), Some("3"));
+        assert_eq!(items[0].price.as_deref(), Some("19.99"));
+        assert_eq!(items[1].sku.as_deref(), Some("B"));
+        assert_eq!(items[1].price.as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn user_defined_key_aliases() {
+        let aliases = parse_key_aliases(" sku code : sku,id:product_ref, broken,:name");
+        assert_eq!(
+            aliases,
+            vec![
+                ("sku_code".to_string(), "sku".to_string()),
+                ("id".to_string(), "product_ref".to_string()),
+            ]
+        );
+        let items = build_items(
+            &[dict(&[("sku code", "A"), ("id", "42")])],
+            &ItemContext::default(),
+            &aliases,
+        );
+        assert_eq!(items[0].sku.as_deref(), Some("A"));
+        assert_eq!(
+            items[0].custom_parameters,
+            Some(vec![("product_ref".to_string(), "42".to_string())])
+        );
+    }
+
+    #[test]
+    fn items_outside_a_list_have_no_index() {
+        let items = build_items(&[dict(&[("sku", "A")])], &ItemContext::default(), &[]);
+        assert_eq!(items[0].index, None);
+    }
+}
@@ -1,33 +1,63 @@
 use crate::exports::edgee::components::data_collection::{
     Data, Dict, EdgeeRequest, Event, HttpMethod,
 };
+use currency::{apply_item_currencies, convert_to_reporting_currency};
+use dedup::{dedupe_transaction, is_recent_transaction, TRANSACTION_EVENTS};
+use event_time::now_micros;
 use exports::edgee::components::data_collection::{Consent, Guest};
 use ga_payload::{GaPayload, Product};
+use items::{
+    build_items, finalize_custom_parameters, items_value, prepare_refund, ItemContext, VALUE_EVENTS,
+};
+use page::{search_term, url_host, ContentGroupSource};
+use properties::{apply_event_properties, apply_user_properties};
+use settings::Settings;
 use std::collections::HashMap;
+use user_id::user_identity;
+mod bot;
+mod currency;
+mod dedup;
+mod event_time;
 mod ga_payload;
+mod items;
+mod linker;
+mod locale;
+mod page;
+mod properties;
+mod querystring;
+mod sampling;
+mod screen;
+mod settings;
+#[cfg(test)]
+mod snapshot_tests;
+#[cfg(test)]
+mod test_helpers;
+mod traffic;
+mod user_agent;
+mod user_id;
 
 wit_bindgen::generate!({world: "data-collection", path: ".edgee/wit", generate_all});
 export!(GaComponent);
 
 struct GaComponent;
 
+/// GA4 recommended events with a `method` parameter.
+const METHOD_EVENTS: [&str; 2] = ["login", "sign_up"];
+
 impl Guest for GaComponent {
     fn page(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, String> {
         if let Data::Page(ref data) = edgee_event.data {
-            let mut ga = GaPayload::new(&edgee_event, settings, "page_view".to_string())
+            let settings = Settings::new(settings).map_err(|e| e.to_string())?;
+            let mut ga = GaPayload::new(&edgee_event, &settings, "page_view".to_string())
                 .map_err(|e| e.to_string())?;
 
             ga.document_location = data.url.clone();
             ga.document_title = data.title.clone();
-            if !data.referrer.clone().starts_with(&data.url) {
-                ga.document_referrer = Some(data.referrer.clone());
-            }
+            ga.set_referrer(&data.referrer, &data.url, &settings);
 
-            let mut event_parameter_string = HashMap::new();
+            let mut event_parameter_string = ga.event_parameter_string.take().unwrap_or_default();
             event_parameter_string.insert("event_id".to_string(), edgee_event.uuid.clone());
 
-            let mut event_parameter_number = HashMap::new();
-
             if !data.name.is_empty() {
                 event_parameter_string.insert("page_name".to_string(), data.name.clone());
             }
@@ -36,6 +66,16 @@ impl Guest for GaComponent {
                 event_parameter_string.insert("page_category".to_string(), data.category.clone());
             }
 
+            let content_group = match settings.content_group_source {
+                ContentGroupSource::Category => &data.category,
+                ContentGroupSource::Name => &data.name,
+                ContentGroupSource::None => "",
+            };
+            if !content_group.is_empty() {
+                event_parameter_string
+                    .insert("content_group".to_string(), content_group.to_string());
+            }
+
             if !data.keywords.is_empty() {
                 event_parameter_string.insert("page_keywords".to_string(), data.keywords.join(","));
             }
@@ -52,23 +92,33 @@ impl Guest for GaComponent {
             };
             event_parameter_string.insert("edgee_consent".to_string(), consent.to_string());
 
-            for (key, value) in data.properties.iter() {
-                let key = key.replace(" ", "_");
-                if value.parse::<f64>().is_ok() {
-                    event_parameter_number.insert(key, value.parse().unwrap());
-                } else if key == "currency" {
-                    ga.currency_code = Some(value.clone());
-                } else {
-                    event_parameter_string.insert(key, value.clone());
-                }
+            if let Some(term) =
+                search_term(&data.search, &data.url, &settings.search_query_parameters)
+            {
+                event_parameter_string.insert("search_term".to_string(), term);
             }
 
             ga.event_parameter_string = Some(event_parameter_string);
-            if !event_parameter_number.is_empty() {
-                ga.event_parameter_number = Some(event_parameter_number);
+            apply_event_properties(&mut ga, &data.properties);
+
+            if let Some(rates) = &settings.exchange_rates {
+                convert_to_reporting_currency(&mut ga, &mut [], rates);
             }
 
-            Ok(build_edgee_request(ga, vec![]).map_err(|e| e.to_string())?)
+            // site search, like GA4 enhanced measurement: a view_search_results event in the same hit
+            let mut extra_events = vec![];
+            let term = ga
+                .event_parameter_string
+                .as_ref()
+                .and_then(|params| params.get("search_term"));
+            if let (true, Some(term)) = (settings.search_results_event, term) {
+                extra_events.push(vec![
+                    ("en".to_string(), "view_search_results".to_string()),
+                    ("ep.search_term".to_string(), term.clone()),
+                ]);
+            }
+
+            Ok(build_edgee_request(ga, vec![], &extra_events).map_err(|e| e.to_string())?)
         } else {
             Err("Missing page data".to_string())
         }
@@ -79,11 +129,21 @@ impl Guest for GaComponent {
             if data.name.is_empty() {
                 return Err("Track is not set".to_string());
             }
+            if data.name == "refund"
+                && !data
+                    .properties
+                    .iter()
+                    .any(|(key, value)| key == "transaction_id" && !value.trim().is_empty())
+            {
+                return Err("Missing transaction_id for refund event".to_string());
+            }
 
-            let mut ga = GaPayload::new(&edgee_event, settings, data.name.clone())
+            let settings = Settings::new(settings).map_err(|e| e.to_string())?;
+
+            let mut ga = GaPayload::new(&edgee_event, &settings, data.name.clone())
                 .map_err(|e| e.to_string())?;
 
-            let mut event_parameter_string = HashMap::new();
+            let mut event_parameter_string = ga.event_parameter_string.take().unwrap_or_default();
             event_parameter_string.insert("event_id".to_string(), edgee_event.uuid.clone());
 
             let consent = match edgee_event.consent {
@@ -94,66 +154,64 @@ impl Guest for GaComponent {
             };
             event_parameter_string.insert("edgee_consent".to_string(), consent.to_string());
 
-            let mut event_parameter_number = HashMap::new();
-
-            for (key, value) in data.properties.iter() {
-                let key = key.replace(" ", "_");
-                if key == "engagement_time" {
-                    ga.engagement_time = Some(value.clone());
-                } else if key == "currency" {
-                    ga.currency_code = Some(value.clone());
-                } else if value.parse::<f64>().is_ok() {
-                    event_parameter_number.insert(key, value.parse().unwrap());
-                } else {
-                    event_parameter_string.insert(key, value.clone());
+            ga.event_parameter_string = Some(event_parameter_string);
+            apply_event_properties(&mut ga, &data.properties);
+
+            if let Some(max_age) = settings.transaction_dedup_max_age {
+                let transaction_id = data
+                    .properties
+                    .iter()
+                    .find(|(key, value)| key == "transaction_id" && !value.trim().is_empty());
+                if let Some((_, transaction_id)) = transaction_id {
+                    if TRANSACTION_EVENTS.contains(&data.name.as_str())
+                        && is_recent_transaction(
+                            edgee_event.timestamp_micros,
+                            now_micros(),
+                            max_age,
+                        )
+                    {
+                        dedupe_transaction(
+                            &mut ga,
+                            &settings.measurement_id,
+                            transaction_id.trim(),
+                        );
+                    }
                 }
             }
 
-            ga.event_parameter_string = Some(event_parameter_string);
-            if !event_parameter_number.is_empty() {
-                ga.event_parameter_number = Some(event_parameter_number);
+            let item_context = ItemContext::from_properties(&data.properties);
+            let mut ga_items =
+                build_items(&data.products, &item_context, &settings.product_key_aliases);
+            if data.name == "refund" {
+                prepare_refund(&mut ga, &mut ga_items);
+            }
+            apply_item_currencies(&mut ga, &mut ga_items, settings.exchange_rates.as_ref());
+
+            if VALUE_EVENTS.contains(&data.name.as_str()) {
+                let has_explicit_value = ga
+                    .event_parameter_number
+                    .as_ref()
+                    .is_some_and(|params| params.contains_key("value"));
+                if !has_explicit_value || !settings.explicit_value_override {
+                    if let Some(value) = items_value(&ga_items) {
+                        ga.event_parameter_number
+                            .get_or_insert_with(HashMap::new)
+                            .insert("value".to_string(), value);
+                    }
+                }
             }
 
-            let ga_items: Vec<Product> = data
-                .products
-                .iter()
-                .map(|product| {
-                    let mut p = Product::default();
-                    let mut p_custom_params = Vec::new();
-
-                    for (key, value) in product.iter() {
-                        let key = key.replace(" ", "_");
-                        match key.as_str() {
-                            "sku" => p.sku = Some(value.clone()),
-                            "name" => p.name = Some(value.clone()),
-                            "affiliation" => p.affiliation = Some(value.clone()),
-                            "coupon" => p.coupon = Some(value.clone()),
-                            "discount" => p.discount = Some(value.clone()),
-                            "index" => p.index = Some(value.clone()),
-                            "brand" => p.brand = Some(value.clone()),
-                            "category" => p.category = Some(value.clone()),
-                            "category2" => p.category2 = Some(value.clone()),
-                            "category3" => p.category3 = Some(value.clone()),
-                            "category4" => p.category4 = Some(value.clone()),
-                            "category5" => p.category5 = Some(value.clone()),
-                            "list_id" => p.list_id = Some(value.clone()),
-                            "list_name" => p.list_name = Some(value.clone()),
-                            "variant" => p.variant = Some(value.clone()),
-                            "location_id" => p.location_id = Some(value.clone()),
-                            "price" => p.price = Some(value.clone()),
-                            "quantity" => p.quantity = Some(value.clone()),
-                            _ => p_custom_params.push((key, value.clone())),
-                        }
-                    }
+            if let Some(rates) = &settings.exchange_rates {
+                convert_to_reporting_currency(&mut ga, &mut ga_items, rates);
+            }
 
-                    if !p_custom_params.is_empty() {
-                        p.custom_parameters = Some(p_custom_params);
-                    }
-                    p
-                })
-                .collect();
+            finalize_custom_parameters(
+                &mut ga_items,
+                settings.item_parameter_limit,
+                settings.item_custom_metrics,
+            );
 
-            Ok(build_edgee_request(ga, ga_items).map_err(|e| e.to_string())?)
+            Ok(build_edgee_request(ga, ga_items, &[]).map_err(|e| e.to_string())?)
         } else {
             Err("Missing track data".to_string())
         }
@@ -161,211 +219,66 @@ impl Guest for GaComponent {
 
     fn user(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, String> {
         if let Data::User(ref data) = edgee_event.data {
-            let mut ga = GaPayload::new(&edgee_event, settings, "user".to_string())
+            let settings = Settings::new(settings).map_err(|e| e.to_string())?;
+            let mut ga = GaPayload::new(&edgee_event, &settings, settings.user_event_name.clone())
                 .map_err(|e| e.to_string())?;
 
-            // user
-            let mut user_property_string: HashMap<String, String> = HashMap::new();
-            let mut user_property_number: HashMap<String, f64> = HashMap::new();
-            if !data.anonymous_id.is_empty() {
-                ga.user_id = Some(data.anonymous_id.clone());
-            }
-            if !data.user_id.is_empty() {
-                ga.user_id = Some(data.user_id.clone());
-                if !data.anonymous_id.is_empty() {
-                    user_property_string
-                        .insert("anonymous_id".to_string(), data.anonymous_id.clone());
-                }
+            // user: the user call data replaces the user of the event context
+            let (user_id, anonymous_id) =
+                user_identity(&data.user_id, &data.anonymous_id, &settings);
+            ga.user_id = user_id;
+            ga.user_property_string = None;
+            ga.user_property_number = None;
+            let mut user_properties = data.properties.clone();
+            if let Some(anonymous_id) = anonymous_id {
+                user_properties.insert(0, ("anonymous_id".to_string(), anonymous_id));
             }
 
-            // user properties
-            if !data.properties.is_empty() {
-                for (key, value) in data.properties.clone().iter() {
-                    // if key has a space, replace by a _
-                    let key = key.replace(" ", "_");
-                    if value.parse::<f64>().is_ok() {
-                        user_property_number.insert(key, value.parse().unwrap());
-                    } else {
-                        user_property_string.insert(key, value.clone());
-                    }
-                }
+            // the `method` of a login or a sign up (ex: Google) describes the event, not the user
+            if METHOD_EVENTS.contains(&settings.user_event_name.as_str()) {
+                let (method, properties): (Dict, Dict) = user_properties
+                    .into_iter()
+                    .partition(|(key, _)| key == "method");
+                apply_event_properties(&mut ga, &method);
+                user_properties = properties;
             }
 
-            if !user_property_string.is_empty() {
-                ga.user_property_string = Some(user_property_string);
-            }
-            if !user_property_number.is_empty() {
-                ga.user_property_number = Some(user_property_number);
-            }
+            apply_user_properties(&mut ga, &user_properties);
 
-            Ok(build_edgee_request(ga, vec![]).map_err(|e| e.to_string())?)
+            Ok(build_edgee_request(ga, vec![], &[]).map_err(|e| e.to_string())?)
         } else {
             Err("Missing user data".to_string())
         }
     }
 }
 
-fn build_edgee_request(ga: GaPayload, ga_items: Vec<Product>) -> anyhow::Result<EdgeeRequest> {
-    let headers = vec![(String::from("content-length"), String::from("0"))];
-
-    let mut querystring = serde_qs::to_string(&ga)?;
-    querystring = cleanup_querystring(&querystring)?;
-
-    if !ga_items.is_empty() {
-        // add the items to the querystring, to do so, each item is converted to a string and added to the querystring
-        // an item starts with &pr following by the item number (for example &pr1, &pr2, &pr3, etc.)
-        // then, the value of the item is one string with the item parameters separated by ~
-        // example: &pr1=id123456~nmTshirtbrThyngster~camen~c2shirts~pr129.99~k0currency~v0JPY~k1stock~v1yes
-        // then the string has to be urlencoded
-        let mut item_strings = Vec::new();
-        for (index, item) in ga_items.iter().enumerate() {
-            let mut item_parts = Vec::new();
-
-            if let Some(sku) = &item.sku {
-                item_parts.push(format!("id{sku}"));
-            }
-            if let Some(name) = &item.name {
-                item_parts.push(format!("nm{name}"));
-            }
-            if let Some(brand) = &item.brand {
-                item_parts.push(format!("br{brand}"));
-            }
-            if let Some(category) = &item.category {
-                item_parts.push(format!("ca{category}"));
-            }
-            if let Some(price) = &item.price {
-                item_parts.push(format!("pr{price}"));
-            }
-            if let Some(affiliation) = &item.affiliation {
-                item_parts.push(format!("af{affiliation}"));
-            }
-            if let Some(coupon) = &item.coupon {
-                item_parts.push(format!("cp{coupon}"));
-            }
-            if let Some(discount) = &item.discount {
-                item_parts.push(format!("ds{discount}"));
-            }
-            if let Some(index_val) = &item.index {
-                item_parts.push(format!("lp{index_val}"));
-            }
-            if let Some(category2) = &item.category2 {
-                item_parts.push(format!("c2{category2}"));
-            }
-            if let Some(category3) = &item.category3 {
-                item_parts.push(format!("c3{category3}"));
-            }
-            if let Some(category4) = &item.category4 {
-                item_parts.push(format!("c4{category4}"));
-            }
-            if let Some(category5) = &item.category5 {
-                item_parts.push(format!("c5{category5}"));
-            }
-            if let Some(list_id) = &item.list_id {
-                item_parts.push(format!("li{list_id}"));
-            }
-            if let Some(list_name) = &item.list_name {
-                item_parts.push(format!("ln{list_name}"));
-            }
-            if let Some(variant) = &item.variant {
-                item_parts.push(format!("va{variant}"));
-            }
-            if let Some(location_id) = &item.location_id {
-                item_parts.push(format!("lo{location_id}"));
-            }
-            if let Some(quantity) = &item.quantity {
-                item_parts.push(format!("qt{quantity}"));
-            }
-
-            // Add custom parameters if present
-            if let Some(custom_params) = &item.custom_parameters {
-                for (param_index, (key, value)) in custom_params.iter().enumerate() {
-                    item_parts.push(format!("k{param_index}{key}"));
-                    item_parts.push(format!("v{param_index}{value}"));
-                }
-            }
-
-            // Join all parts with ~ and URL encode
-            let item_value = urlencoding::encode(&item_parts.join("~")).into_owned();
-            item_strings.push(format!("&pr{}={}", index + 1, item_value));
-        }
-
-        // Add all item strings to querystring
-        let items_qs = item_strings.join("");
-        querystring = format!("{querystring}{items_qs}");
-    }
+/// Build the `/g/collect` request. Extra events (ex: view_search_results) are batched in the same
+/// hit: one line per event in the request body.
+fn build_edgee_request(
+    mut ga: GaPayload,
+    ga_items: Vec<Product>,
+    extra_events: &[Vec<(String, String)>],
+) -> anyhow::Result<EdgeeRequest> {
+    if ga.document_hostname.is_none() {
+        ga.document_hostname = url_host(&ga.document_location);
+    }
+
+    let (querystring, body) = if extra_events.is_empty() {
+        (querystring::to_querystring(&ga, &ga_items)?, String::new())
+    } else {
+        querystring::to_batch(&ga, &ga_items, extra_events)?
+    };
+    let headers = vec![(String::from("content-length"), body.len().to_string())];
 
     Ok(EdgeeRequest {
         method: HttpMethod::Post,
         url: format!("https://www.google-analytics.com/g/collect?{querystring}"),
         headers,
         forward_client_headers: true,
-        body: String::new(),
+        body,
     })
 }
 
-// change every parameters like ep[page_type]=checkout to ep.page_type=checkout. check only the ep, epn, up, upn parameters
-fn cleanup_querystring(ga4_qs: &str) -> anyhow::Result<String> {
-    let mut cleaned_qs = String::new();
-    let mut i = 0;
-    let chars: Vec<char> = ga4_qs.chars().collect();
-    let len = chars.len();
-
-    while i < len {
-        if i + 2 < len {
-            // Check for "ep[", "epn[", "up[", or "upn[" patterns
-            if (chars[i] == 'e' && chars[i + 1] == 'p' && chars[i + 2] == '[')
-                || (chars[i] == 'e'
-                    && chars[i + 1] == 'p'
-                    && chars[i + 2] == 'n'
-                    && i + 3 < len
-                    && chars[i + 3] == '[')
-                || (chars[i] == 'u' && chars[i + 1] == 'p' && chars[i + 2] == '[')
-                || (chars[i] == 'u'
-                    && chars[i + 1] == 'p'
-                    && chars[i + 2] == 'n'
-                    && i + 3 < len
-                    && chars[i + 3] == '[')
-            {
-                // Append the base part of the key (e.g. "ep", "epn", "up", or "upn")
-                let base = if chars[i] == 'e' {
-                    if chars[i + 2] == 'n' {
-                        "epn"
-                    } else {
-                        "ep"
-                    }
-                } else if chars[i + 2] == 'n' {
-                    "upn"
-                } else {
-                    "up"
-                };
-                cleaned_qs.push_str(base);
-                cleaned_qs.push('.'); // Replace "[" with "."
-
-                // Skip the characters that form "ep[" or "epn["
-                if base == "ep" || base == "up" {
-                    i += 3; // Skip "ep[" or "up["
-                } else {
-                    i += 4; // Skip "epn[" or "upn["
-                }
-
-                // Capture the content between brackets and append
-                while i < len && chars[i] != ']' {
-                    cleaned_qs.push(chars[i]);
-                    i += 1;
-                }
-                i += 1; // Skip the closing bracket ']'
-                continue;
-            }
-        }
-
-        // Add the current character to the output if no special pattern was found
-        cleaned_qs.push(chars[i]);
-        i += 1;
-    }
-
-    Ok(cleaned_qs)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -376,46 +289,6 @@ mod tests {
     use pretty_assertions::assert_eq;
     use uuid::Uuid;
 
-    #[test]
-    fn cleanup_querystring_replaces_correctly() {
-        let input = "ep[page_type]=checkout&epn[page_number]=1&up[user_id]=123&upn[user_age]=30";
-        let expected = "ep.page_type=checkout&epn.page_number=1&up.user_id=123&upn.user_age=30";
-        let result = cleanup_querystring(input).unwrap();
-        assert_eq!(result, expected);
-    }
-
-    #[test]
-    fn cleanup_querystring_handles_empty_string() {
-        let input = "";
-        let expected = "";
-        let result = cleanup_querystring(input).unwrap();
-        assert_eq!(result, expected);
-    }
-
-    #[test]
-    fn cleanup_querystring_handles_no_replacements() {
-        let input = "some_param=value&another_param=value2";
-        let expected = "some_param=value&another_param=value2";
-        let result = cleanup_querystring(input).unwrap();
-        assert_eq!(result, expected);
-    }
-
-    #[test]
-    fn cleanup_querystring_handles_partial_replacements() {
-        let input = "ep[page_type]=checkout&some_param=value";
-        let expected = "ep.page_type=checkout&some_param=value";
-        let result = cleanup_querystring(input).unwrap();
-        assert_eq!(result, expected);
-    }
-
-    #[test]
-    fn cleanup_querystring_handles_multiple_replacements() {
-        let input = "ep[page_type]=checkout&ep[page_name]=home&up[user_id]=123";
-        let expected = "ep.page_type=checkout&ep.page_name=home&up.user_id=123";
-        let result = cleanup_querystring(input).unwrap();
-        assert_eq!(result, expected);
-    }
-
     fn sample_user_data(edgee_id: String) -> UserData {
         UserData {
             user_id: "123".to_string(),
@@ -589,6 +462,34 @@ mod tests {
         vec![("ga_measurement_id".to_string(), "abc".to_string())]
     }
 
+    #[test]
+    fn page_with_search_results_event() {
+        let mut event = sample_page_event(
+            Some(Consent::Granted),
+            "abc".to_string(),
+            "fr".to_string(),
+            true,
+        );
+        if let Data::Page(ref mut data) = event.data {
+            data.search = "?test=1&q=shoes".to_string();
+        }
+        let mut settings = sample_settings();
+        settings.push(("search_results_event".to_string(), "true".to_string()));
+        let edgee_request = GaComponent::page(event, settings).unwrap();
+
+        let lines: Vec<&str> = edgee_request.body.split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("en=page_view&"));
+        assert_eq!(lines[1], "en=view_search_results&ep.search_term=shoes");
+        assert_eq!(
+            edgee_request.headers,
+            vec![(
+                "content-length".to_string(),
+                edgee_request.body.len().to_string()
+            )]
+        );
+    }
+
     #[test]
     fn page_with_consent() {
         let event = sample_page_event(
@@ -0,0 +1,326 @@
+//! Cross-domain linker: the `_gl` parameter gtag.js adds to the links between the domains of a
+//! site, so that the journey keeps the same client ID and session.
+//! ex: _gl=1*1xh2g7q*_ga*MTIzNDU2Nzg5LjE3MzUwMDAwMDA.*_ga_TEST123*czE3MzU2ODkwMDAkbzIkZzE.
+//!
+//! The first field is the version, the second a fingerprint of the browser and of the current
+//! minute, then come key/value pairs with base64 encoded values.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::exports::edgee::components::data_collection::Client;
+use crate::locale::normalize_locale;
+
+/// A linker is valid for 3 minutes: the fingerprint of the current minute and of the 2 previous ones.
+const LINKER_MINUTES: i64 = 3;
+
+/// Client ID and session carried by a valid linker.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Linker {
+    pub client_id: String,
+    pub session: Option<LinkedSession>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LinkedSession {
+    pub session_id: String,
+    pub session_count: Option<String>,
+}
+
+/// Raw `_gl` value of a page, from its query string (`page.search`, or the URL) or its fragment.
+pub(crate) fn find_linker<'a>(search: &'a str, url: &'a str) -> Option<&'a str> {
+    let url_query = url.split_once('?').map_or("", |(_, query)| query);
+    [search.trim_start_matches('?'), url_query]
+        .into_iter()
+        .flat_map(|query| query.split(['&', '#']))
+        .find_map(|pair| pair.strip_prefix("_gl="))
+        .filter(|value| !value.is_empty())
+}
+
+/// Validate a `_gl` value and read the client ID (`_ga`) and the session of the measurement ID
+/// (`_ga_XXXXXXXXXX`) it carries.
+///
+/// The fingerprint covers the user agent, the timezone offset and the language of the browser.
+/// Only the user agent is known exactly: every timezone offset and the usual spellings of the
+/// locale are tried, which still leaves about a thousand candidates out of 2^32 fingerprints.
+pub(crate) fn read_linker(
+    linker: &str,
+    measurement_id: &str,
+    client: &Client,
+    timestamp: i64,
+) -> Option<Linker> {
+    let mut fields = linker.split('*');
+    if fields.next()? != "1" {
+        return None;
+    }
+    let fingerprint = fields.next()?;
+    let pairs: Vec<&str> = fields.collect();
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return None;
+    }
+    if !is_valid_fingerprint(fingerprint, &pairs.join("*"), client, timestamp) {
+        return None;
+    }
+
+    let session_key = format!("_ga_{}", measurement_id.trim_start_matches("G-"));
+    let mut client_id = None;
+    let mut session = None;
+    for pair in pairs.chunks(2) {
+        let value = decode_value(pair[1]);
+        if pair[0] == "_ga" {
+            client_id = value.and_then(|value| parse_client_id(&value));
+        } else if pair[0] == session_key {
+            session = value.and_then(|value| parse_session(&value));
+        }
+    }
+
+    Some(Linker {
+        client_id: client_id?,
+        session,
+    })
+}
+
+fn is_valid_fingerprint(fingerprint: &str, pairs: &str, client: &Client, timestamp: i64) -> bool {
+    if client.user_agent.is_empty() {
+        return false;
+    }
+    let minute = timestamp.div_euclid(60);
+    let languages = language_candidates(&normalize_locale(&client.locale).unwrap_or_default());
+    // JavaScript timezone offsets, in minutes: UTC-12:00 is 720, UTC+14:00 is -840
+    (0..LINKER_MINUTES).any(|elapsed| {
+        (-840..=720).step_by(15).any(|offset| {
+            languages.iter().any(|language| {
+                let source = format!(
+                    "{}*{offset}*{language}*{}*{pairs}",
+                    client.user_agent,
+                    minute - elapsed
+                );
+                to_base36(crc32fast::hash(source.as_bytes())) == fingerprint
+            })
+        })
+    })
+}
+
+/// `navigator.language` spellings of a locale. ex: fr-fr -> fr-fr, fr-FR, fr
+fn language_candidates(locale: &str) -> Vec<String> {
+    let mut candidates = vec![locale.to_string()];
+    if let Some((language, region)) = locale.split_once('-') {
+        candidates.push(format!(
+            "{}-{}",
+            language.to_lowercase(),
+            region.to_uppercase()
+        ));
+        candidates.push(language.to_lowercase());
+    }
+    candidates.dedup();
+    candidates
+}
+
+fn to_base36(mut value: u32) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.push(std::char::from_digit(value % 36, 36).unwrap());
+        value /= 36;
+        if value == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+/// Linker values are base64 encoded, with `.` instead of `=` (and URL safe characters).
+fn decode_value(value: &str) -> Option<String> {
+    let value: String = value
+        .chars()
+        .map(|c| match c {
+            '.' => '=',
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+    String::from_utf8(STANDARD.decode(value).ok()?).ok()
+}
+
+/// ex: 123456789.1735000000, or the `_ga` cookie value GA1.1.123456789.1735000000
+fn parse_client_id(value: &str) -> Option<String> {
+    let parts: Vec<&str> = value.split('.').collect();
+    let client_id = &parts[parts.len().saturating_sub(2)..];
+    let valid = client_id.len() == 2
+        && client_id
+            .iter()
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    valid.then(|| client_id.join("."))
+}
+
+/// Session cookie values, with or without their `GS1.1.` / `GS2.1.` prefix:
+/// - 1735689000.2.1.1735689100.0.0.0 (session ID, session count, ...)
+/// - s1735689000$o2$g1$t1735689100 (`s` session ID, `o` session count, ...)
+fn parse_session(value: &str) -> Option<LinkedSession> {
+    let value = match value.strip_prefix("GS") {
+        Some(rest) => rest.splitn(3, '.').nth(2)?,
+        None => value,
+    };
+    let (session_id, session_count) = if value.contains('$') {
+        let field = |prefix: char| {
+            value
+                .split('$')
+                .find_map(|field| field.strip_prefix(prefix))
+                .map(String::from)
+        };
+        (field('s')?, field('o'))
+    } else {
+        let mut fields = value.split('.');
+        (fields.next()?.to_string(), fields.next().map(String::from))
+    };
+    if session_id.is_empty() || !session_id.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(LinkedSession {
+        session_id,
+        session_count: session_count
+            .filter(|count| !count.is_empty() && count.chars().all(|c| c.is_ascii_digit())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+    use base64::engine::general_purpose::URL_SAFE;
+    use pretty_assertions::assert_eq;
+
+    const USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7)";
+    const TIMESTAMP: i64 = 1_735_689_600;
+
+    fn client() -> Client {
+        let mut client = test_helpers::client(USER_AGENT);
+        client.locale = "fr-FR,fr;q=0.9".to_string();
+        client.timezone = "Europe/Paris".to_string();
+        client
+    }
+
+    fn encode(value: &str) -> String {
+        URL_SAFE.encode(value).replace('=', ".")
+    }
+
+    /// Build a linker the way gtag.js does, in a browser set to `fr-FR` and UTC+1.
+    fn linker(pairs: &[(&str, &str)], minute: i64) -> String {
+        let pairs = pairs
+            .iter()
+            .map(|(key, value)| format!("{key}*{}", encode(value)))
+            .collect::<Vec<_>>()
+            .join("*");
+        let source = format!("{USER_AGENT}*-60*fr-FR*{minute}*{pairs}");
+        format!(
+            "1*{}*{pairs}",
+            to_base36(crc32fast::hash(source.as_bytes()))
+        )
+    }
+
+    #[test]
+    fn finds_the_linker_parameter() {
+        assert_eq!(find_linker("?a=1&_gl=1*abc", ""), Some("1*abc"));
+        assert_eq!(
+            find_linker("", "https://shop.com/?x=1#_gl=1*abc"),
+            Some("1*abc")
+        );
+        assert_eq!(find_linker("?gl=1", "https://shop.com/"), None);
+    }
+
+    #[test]
+    fn reads_a_valid_linker() {
+        let gl = linker(
+            &[
+                ("_ga", "GA1.1.123456789.1735000000"),
+                ("_ga_TEST123", "GS2.1.s1735689000$o2$g1$t1735689100"),
+                ("_gcl_aw", "GCL.1735689000.abc"),
+            ],
+            TIMESTAMP / 60 - 1,
+        );
+        assert_eq!(
+            read_linker(&gl, "G-TEST123", &client(), TIMESTAMP),
+            Some(Linker {
+                client_id: "123456789.1735000000".to_string(),
+                session: Some(LinkedSession {
+                    session_id: "1735689000".to_string(),
+                    session_count: Some("2".to_string()),
+                }),
+            })
+        );
+    }
+
+    /// Linker of Chrome 124 on macOS, set to `fr-FR` and Europe/Paris, at 2024-12-31T23:59:30Z.
+    /// Generated in JavaScript with the gtag.js linker algorithm, independently of `linker()`:
+    /// `Date#getTimezoneOffset`, crc32 `toString(36)`, web-safe base64 values with `.` padding.
+    #[test]
+    fn reads_a_gtag_linker() {
+        let mut client = client();
+        client.user_agent = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36".to_string();
+        let gl = "1*1ootb9n*_ga*R0ExLjEuMTA4NjcwMDUyLjE3MzUwMDAwMDA.*_ga_TEST123*R1MxLjEuMTczNTY4OTAwMC4zLjEuMTczNTY4OTEwMC4wLjAuMA..";
+        assert_eq!(
+            read_linker(gl, "G-TEST123", &client, TIMESTAMP),
+            Some(Linker {
+                client_id: "108670052.1735000000".to_string(),
+                session: Some(LinkedSession {
+                    session_id: "1735689000".to_string(),
+                    session_count: Some("3".to_string()),
+                }),
+            })
+        );
+        assert_eq!(read_linker(gl, "G-TEST123", &client, TIMESTAMP + 180), None);
+    }
+
+    #[test]
+    fn session_of_another_measurement_id_is_ignored() {
+        let gl = linker(
+            &[
+                ("_ga", "123456789.1735000000"),
+                ("_ga_OTHER", "1735689000.2.1.1735689100.0.0.0"),
+            ],
+            TIMESTAMP / 60,
+        );
+        let linker = read_linker(&gl, "G-TEST123", &client(), TIMESTAMP).unwrap();
+        assert_eq!(linker.client_id, "123456789.1735000000");
+        assert_eq!(linker.session, None);
+    }
+
+    #[test]
+    fn expired_or_tampered_linkers_are_rejected() {
+        let pairs = [("_ga", "123456789.1735000000")];
+        let expired = linker(&pairs, TIMESTAMP / 60 - 3);
+        assert_eq!(
+            read_linker(&expired, "G-TEST123", &client(), TIMESTAMP),
+            None
+        );
+
+        let valid = linker(&pairs, TIMESTAMP / 60);
+        let tampered = valid.replace(&encode("123456789.1735000000"), &encode("1.1"));
+        assert_eq!(
+            read_linker(&tampered, "G-TEST123", &client(), TIMESTAMP),
+            None
+        );
+
+        let mut other_browser = client();
+        other_browser.user_agent = "curl/8.0".to_string();
+        assert_eq!(
+            read_linker(&valid, "G-TEST123", &other_browser, TIMESTAMP),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_session_cookie_formats() {
+        assert_eq!(
+            parse_session("GS1.1.1735689000.2.1.1735689100.0.0.0"),
+            Some(LinkedSession {
+                session_id: "1735689000".to_string(),
+                session_count: Some("2".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_session("s1735689000$o3$g1").map(|s| s.session_count),
+            Some(Some("3".to_string()))
+        );
+        assert_eq!(parse_session("GS1.1.abc.2"), None);
+    }
+}
@@ -0,0 +1,81 @@
+//! Browser language (`ul`): lowercase BCP 47 language tag, like gtag.js. ex: fr-fr
+
+/// Normalize a locale, or the preferred language of an Accept-Language value, to a lowercase
+/// BCP 47 language tag. `None` when there is no valid language tag.
+/// ex: fr_FR -> fr-fr, fr-FR,fr;q=0.9 -> fr-fr, de_DE.UTF-8 -> de-de, * -> None
+pub(crate) fn normalize_locale(locale: &str) -> Option<String> {
+    let mut best: Option<(String, f64)> = None;
+    for entry in locale.split(',') {
+        let mut parts = entry.split(';');
+        let Some(tag) = parts.next().and_then(normalize_tag) else {
+            continue;
+        };
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f64>().ok())
+            .filter(|q| *q > 0.0 && *q <= 1.0);
+        match (quality, &best) {
+            (Some(q), Some((_, best_q))) if q <= *best_q => {}
+            (Some(q), _) => best = Some((tag, q)),
+            (None, _) => {}
+        }
+    }
+    best.map(|(tag, _)| tag)
+}
+
+/// ex: en_US.UTF-8 -> en-us
+fn normalize_tag(tag: &str) -> Option<String> {
+    // POSIX locales: codeset and modifier. ex: de_DE.UTF-8, fr_FR@euro
+    let tag = tag.split(['.', '@']).next()?.trim();
+    let tag = tag.replace('_', "-").to_lowercase();
+    let mut subtags = tag.split('-');
+    let language = subtags.next()?;
+    let valid = (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    valid.then_some(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locales() {
+        assert_eq!(normalize_locale("fr-fr").as_deref(), Some("fr-fr"));
+        assert_eq!(normalize_locale("fr_FR").as_deref(), Some("fr-fr"));
+        assert_eq!(normalize_locale(" EN ").as_deref(), Some("en"));
+        assert_eq!(normalize_locale("de_DE.UTF-8").as_deref(), Some("de-de"));
+        assert_eq!(
+            normalize_locale("zh-Hant-TW").as_deref(),
+            Some("zh-hant-tw")
+        );
+        assert_eq!(normalize_locale("es-419").as_deref(), Some("es-419"));
+    }
+
+    #[test]
+    fn accept_language_values() {
+        assert_eq!(
+            normalize_locale("fr-FR,fr;q=0.9,en;q=0.8").as_deref(),
+            Some("fr-fr")
+        );
+        assert_eq!(
+            normalize_locale("en;q=0.5, de-CH ;q=0.8").as_deref(),
+            Some("de-ch")
+        );
+        assert_eq!(normalize_locale("*, it;q=0.7").as_deref(), Some("it"));
+        assert_eq!(normalize_locale("pt;q=0, es;q=0.1").as_deref(), Some("es"));
+    }
+
+    #[test]
+    fn invalid_locales() {
+        assert_eq!(normalize_locale("").as_deref(), None);
+        assert_eq!(normalize_locale("*").as_deref(), None);
+        assert_eq!(normalize_locale("C").as_deref(), None);
+        assert_eq!(normalize_locale("english").as_deref(), None);
+        assert_eq!(normalize_locale("en-verylongsubtag").as_deref(), None);
+        assert_eq!(normalize_locale("fr;q=abc").as_deref(), None);
+    }
+}
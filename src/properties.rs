@@ -0,0 +1,302 @@
+//! Mapping of Edgee properties to GA4 parameters, shared by the page, track and user handlers.
+use std::collections::HashMap;
+
+use crate::currency::normalize_currency;
+use crate::exports::edgee::components::data_collection::Dict;
+use crate::ga_payload::GaPayload;
+use crate::items::{parse_amount, ITEM_CONTEXT_PARAMETERS};
+use crate::page::with_path;
+use crate::screen::parse_size;
+
+/// Identifiers that look like numbers but must be sent as strings. ex: transaction_id = 1001
+const STRING_PARAMETERS: [&str; 5] = [
+    "transaction_id",
+    "coupon",
+    "content_id",
+    "anonymous_id",
+    "user_id",
+];
+
+/// Parameters that are amounts: dropped when they are not a number.
+const AMOUNT_PARAMETERS: [&str; 3] = ["value", "tax", "shipping"];
+
+/// Map event properties to event parameters (`ep.*` / `epn.*`), on top of the ones already set.
+pub(crate) fn apply_event_properties(ga: &mut GaPayload, properties: &Dict) {
+    apply_properties(ga, properties, Scope::Event);
+}
+
+/// Map user properties to user properties (`up.*` / `upn.*`), on top of the ones already set.
+pub(crate) fn apply_user_properties(ga: &mut GaPayload, properties: &Dict) {
+    apply_properties(ga, properties, Scope::User);
+}
+
+/// Parameters the properties without their own GA4 field are sent as.
+#[derive(Clone, Copy)]
+enum Scope {
+    Event,
+    User,
+}
+
+type Parameters<'a> = (
+    &'a mut Option<HashMap<String, String>>,
+    &'a mut Option<HashMap<String, f64>>,
+);
+
+impl Scope {
+    /// String and numeric parameters of the scope.
+    fn parameters(self, ga: &mut GaPayload) -> Parameters<'_> {
+        match self {
+            Scope::Event => (
+                &mut ga.event_parameter_string,
+                &mut ga.event_parameter_number,
+            ),
+            Scope::User => (&mut ga.user_property_string, &mut ga.user_property_number),
+        }
+    }
+}
+
+/// `currency`, `engagement_time`, `traffic_type` and the viewport size have their own GA4 fields,
+/// and `page_path` replaces the path of the page URL, whatever the scope of the properties.
+fn apply_properties(ga: &mut GaPayload, properties: &Dict, scope: Scope) {
+    let (strings, numbers) = scope.parameters(ga);
+    let mut strings = strings.take().unwrap_or_default();
+    let mut numbers = numbers.take().unwrap_or_default();
+    let mut viewport = (None, None);
+
+    for (key, value) in properties.iter() {
+        let key = parameter_name(key);
+        match key.as_str() {
+            "currency" => ga.currency_code = normalize_currency(value),
+            "page_path" if !value.trim().is_empty() => {
+                ga.document_location = with_path(&ga.document_location, value)
+            }
+            "traffic_type" if !value.trim().is_empty() => {
+                ga.traffic_type = Some(value.trim().to_string())
+            }
+            "engagement_time" => {
+                if let Some(time) = parse_amount(value).filter(|time| *time >= 0.0) {
+                    ga.engagement_time = Some(time.round().to_string());
+                }
+            }
+            "viewport_size" => {
+                if let Some(size) = parse_size(value) {
+                    ga.viewport_size = Some(size);
+                }
+            }
+            "viewport_width" => viewport.0 = Some(value.trim()),
+            "viewport_height" => viewport.1 = Some(value.trim()),
+            key if AMOUNT_PARAMETERS.contains(&key) => {
+                if let Some(amount) = parse_amount(value) {
+                    insert_typed(&mut strings, &mut numbers, key, Typed::Number(amount));
+                }
+            }
+            key => insert_typed(&mut strings, &mut numbers, key, typed_value(key, value)),
+        }
+    }
+
+    if let (Some(width), Some(height)) = viewport {
+        if let Some(size) = parse_size(&format!("{width}x{height}")) {
+            ga.viewport_size = Some(size);
+        }
+    }
+
+    let (string_params, number_params) = scope.parameters(ga);
+    *string_params = Some(strings).filter(|params| !params.is_empty());
+    *number_params = Some(numbers).filter(|params| !params.is_empty());
+}
+
+/// Property names with spaces are sent with underscores. ex: "lifetime value" -> lifetime_value
+fn parameter_name(key: &str) -> String {
+    key.replace(" ", "_")
+}
+
+enum Typed {
+    String(String),
+    Number(f64),
+}
+
+/// Numbers are sent as numeric parameters, except identifiers and item context parameters.
+fn typed_value(key: &str, value: &str) -> Typed {
+    if STRING_PARAMETERS.contains(&key) || ITEM_CONTEXT_PARAMETERS.contains(&key) {
+        return Typed::String(value.to_string());
+    }
+    match parse_amount(value) {
+        Some(number) => Typed::Number(number),
+        None => Typed::String(value.to_string()),
+    }
+}
+
+/// A parameter is either a string or a number: the last value wins.
+fn insert_typed(
+    strings: &mut HashMap<String, String>,
+    numbers: &mut HashMap<String, f64>,
+    key: &str,
+    value: Typed,
+) {
+    match value {
+        Typed::String(value) => {
+            numbers.remove(key);
+            strings.insert(key.to_string(), value);
+        }
+        Typed::Number(value) => {
+            strings.remove(key);
+            numbers.insert(key.to_string(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::dict;
+    use pretty_assertions::assert_eq;
+
+    fn strings(entries: &[(&str, &str)]) -> Option<HashMap<String, String>> {
+        Some(
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn numbers(entries: &[(&str, f64)]) -> Option<HashMap<String, f64>> {
+        Some(
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), *value))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn event_properties() {
+        let mut ga = GaPayload::default();
+        ga.event_parameter_string = strings(&[("event_id", "abc"), ("step", "first")]);
+        apply_event_properties(
+            &mut ga,
+            &dict(&[
+                ("currency", "eur"),
+                ("engagement_time", "1200.4"),
+                ("traffic_type", " qa "),
+                ("value", "19.99"),
+                ("tax", "free"),
+                ("transaction_id", "1001"),
+                ("item_list_id", "42"),
+                ("step", "2"),
+                ("cart size", " 3 "),
+                ("label", "NaN"),
+            ]),
+        );
+
+        assert_eq!(ga.currency_code.as_deref(), Some("EUR"));
+        assert_eq!(ga.engagement_time.as_deref(), Some("1200"));
+        assert_eq!(ga.traffic_type.as_deref(), Some("qa"));
+        assert_eq!(
+            ga.event_parameter_string,
+            strings(&[
+                ("event_id", "abc"),
+                ("transaction_id", "1001"),
+                ("item_list_id", "42"),
+                ("label", "NaN"),
+            ])
+        );
+        assert_eq!(
+            ga.event_parameter_number,
+            numbers(&[("value", 19.99), ("step", 2.0), ("cart_size", 3.0)])
+        );
+    }
+
+    #[test]
+    fn page_path_overrides_the_page_url() {
+        let mut ga = GaPayload::default();
+        ga.document_location = "https://shop.com/#/cart".to_string();
+        apply_event_properties(&mut ga, &dict(&[("page_path", "/cart")]));
+        assert_eq!(ga.document_location, "https://shop.com/cart");
+        assert_eq!(ga.event_parameter_string, None);
+    }
+
+    #[test]
+    fn viewport_size() {
+        let mut ga = GaPayload::default();
+        apply_event_properties(&mut ga, &dict(&[("viewport_size", "1280X720")]));
+        assert_eq!(ga.viewport_size.as_deref(), Some("1280x720"));
+
+        let mut ga = GaPayload::default();
+        apply_event_properties(
+            &mut ga,
+            &dict(&[("viewport_width", "390"), ("viewport_height", " 844 ")]),
+        );
+        assert_eq!(ga.viewport_size.as_deref(), Some("390x844"));
+        assert_eq!(ga.event_parameter_number, None);
+
+        let mut ga = GaPayload::default();
+        apply_event_properties(
+            &mut ga,
+            &dict(&[("viewport_size", "full"), ("viewport_width", "390")]),
+        );
+        assert_eq!(ga.viewport_size, None);
+    }
+
+    #[test]
+    fn invalid_engagement_time_is_ignored() {
+        let mut ga = GaPayload::default();
+        apply_event_properties(&mut ga, &dict(&[("engagement_time", "-5")]));
+        assert_eq!(ga.engagement_time, None);
+        assert_eq!(ga.event_parameter_string, None);
+        assert_eq!(ga.event_parameter_number, None);
+    }
+
+    #[test]
+    fn user_properties() {
+        let mut ga = GaPayload::default();
+        ga.user_property_string = strings(&[("anonymous_id", "a-1")]);
+        apply_user_properties(
+            &mut ga,
+            &dict(&[
+                ("user_id", "1002"),
+                ("plan", "premium"),
+                ("lifetime value", "45.5"),
+                ("value", "a lot"),
+                ("currency", "eur"),
+                ("engagement_time", "300"),
+            ]),
+        );
+        assert_eq!(
+            ga.user_property_string,
+            strings(&[
+                ("anonymous_id", "a-1"),
+                ("user_id", "1002"),
+                ("plan", "premium"),
+            ])
+        );
+        assert_eq!(
+            ga.user_property_number,
+            numbers(&[("lifetime_value", 45.5)])
+        );
+        assert_eq!(ga.currency_code.as_deref(), Some("EUR"));
+        assert_eq!(ga.engagement_time.as_deref(), Some("300"));
+        assert_eq!(ga.event_parameter_string, None);
+        assert_eq!(ga.event_parameter_number, None);
+    }
+
+    #[test]
+    fn event_and_user_properties_are_mapped_alike() {
+        let properties = dict(&[
+            ("currency", "usd"),
+            ("value", "12.5"),
+            ("tax", "n/a"),
+            ("transaction_id", "1001"),
+            ("engagement_time", "42"),
+        ]);
+        let mut event = GaPayload::default();
+        apply_event_properties(&mut event, &properties);
+        let mut user = GaPayload::default();
+        apply_user_properties(&mut user, &properties);
+
+        assert_eq!(event.currency_code, user.currency_code);
+        assert_eq!(event.engagement_time, user.engagement_time);
+        assert_eq!(event.event_parameter_string, user.user_property_string);
+        assert_eq!(event.event_parameter_number, user.user_property_number);
+    }
+}
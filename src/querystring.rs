@@ -0,0 +1,690 @@
+//! GA4 `/g/collect` querystring serializer.
+//!
+//! `GaPayload` keeps its parameter names in `#[serde(rename)]` attributes. This serializer walks
+//! the payload with serde and writes every field straight in the GA4 wire format:
+//! - scalar fields become `key=value`
+//! - map fields (`ep`, `epn`, `up`, `upn`) become `ep.key=value`, one parameter per entry, sorted by key
+//! - items are appended as `pr1`, `pr2`, ... (see `encode_item`)
+//!
+//! Keys and values are percent-encoded individually, so brackets, `&`, `=` or `ep[` in user data
+//! can never change the structure of the querystring.
+use std::fmt;
+
+use serde::ser::{self, Impossible, Serialize};
+
+use crate::ga_payload::{GaPayload, Product};
+
+/// Serialize a payload and its items into a `/g/collect` querystring.
+pub(crate) fn to_querystring(ga: &GaPayload, items: &[Product]) -> anyhow::Result<String> {
+    Ok(encode_pairs(&payload_pairs(ga, items)?))
+}
+
+/// Serialize a payload and extra events into a batched `/g/collect` hit: the parameters shared by
+/// all the events go in the querystring, and every event has its own line in the request body,
+/// starting with the event of the payload.
+/// ex: querystring `v=2&tid=G-XXX&cid=...`, body `en=page_view&ep.x=1\r\nen=view_search_results`
+pub(crate) fn to_batch(
+    ga: &GaPayload,
+    items: &[Product],
+    extra_events: &[Vec<(String, String)>],
+) -> anyhow::Result<(String, String)> {
+    let (event, shared): (Vec<_>, Vec<_>) = payload_pairs(ga, items)?
+        .into_iter()
+        .partition(|(key, _)| is_event_parameter(key));
+    let lines: Vec<String> = std::iter::once(&event)
+        .chain(extra_events)
+        .map(|pairs| encode_pairs(pairs))
+        .collect();
+    Ok((encode_pairs(&shared), lines.join("\r\n")))
+}
+
+fn payload_pairs(ga: &GaPayload, items: &[Product]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut pairs = ga.serialize(PayloadSerializer)?;
+    for (index, item) in items.iter().enumerate() {
+        pairs.push((format!("pr{}", index + 1), encode_item(item)));
+    }
+    Ok(pairs)
+}
+
+fn encode_pairs(pairs: &[(String, String)]) -> String {
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", encode_key(key), urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Parameters of the event of the payload that must not apply to the other events of a batch.
+/// ex: `_ss` (session start) and `_fv` (first visit) only flag the page view
+const EVENT_PARAMETERS: [&str; 8] = ["en", "_et", "_ss", "_fv", "_nsi", "_ee", "_c", "cu"];
+
+/// Parameters that describe one event of a batch, instead of the page, the user or the session.
+fn is_event_parameter(key: &str) -> bool {
+    EVENT_PARAMETERS.contains(&key)
+        || key.starts_with("ep.")
+        || key.starts_with("epn.")
+        || (key.starts_with("pr") && key[2..].chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Convert an item into its GA4 string: every parameter is prefixed with its two-letter code and
+/// parameters are separated by `~` (see `escape_item_value` for values containing a `~`).
+/// ex: id123456~nmTshirt~brThyngster~camen~c2shirts~pr129.99~cuJPY~k0stock~v0yes
+pub(crate) fn encode_item(item: &Product) -> String {
+    let mut item_parts = Vec::new();
+
+    let fields = [
+        ("id", &item.sku),
+        ("nm", &item.name),
+        ("br", &item.brand),
+        ("ca", &item.category),
+        ("pr", &item.price),
+        ("cu", &item.currency),
+        ("af", &item.affiliation),
+        ("cp", &item.coupon),
+        ("ds", &item.discount),
+        ("lp", &item.index),
+        ("c2", &item.category2),
+        ("c3", &item.category3),
+        ("c4", &item.category4),
+        ("c5", &item.category5),
+        ("li", &item.list_id),
+        ("ln", &item.list_name),
+        ("va", &item.variant),
+        ("lo", &item.location_id),
+        ("pi", &item.promotion_id),
+        ("pn", &item.promotion_name),
+        ("cn", &item.creative_name),
+        ("cs", &item.creative_slot),
+        ("qt", &item.quantity),
+    ];
+    for (prefix, value) in fields {
+        if let Some(value) = value {
+            item_parts.push(format!("{prefix}{}", escape_item_value(value)));
+        }
+    }
+
+    // Add custom parameters if present, then custom metrics which have numeric values (`vn`)
+    let custom_params = item.custom_parameters.iter().flatten().map(|p| ("v", p));
+    let custom_metrics = item.custom_metrics.iter().flatten().map(|p| ("vn", p));
+    for (param_index, (value_prefix, (key, value))) in
+        custom_params.chain(custom_metrics).enumerate()
+    {
+        item_parts.push(format!("k{param_index}{}", escape_item_value(key)));
+        item_parts.push(format!(
+            "{value_prefix}{param_index}{}",
+            escape_item_value(value)
+        ));
+    }
+
+    item_parts.join("~")
+}
+
+/// Escape a value written inside an item string, the same way gtag.js does: `~` separates item
+/// parameters, so a literal `~` is doubled (`Shirt ~ Blue` is written `Shirt ~~ Blue`).
+/// Control characters (newlines, tabs, ...) are dropped, GA4 would reject the whole item otherwise.
+fn escape_item_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars().filter(|c| !c.is_control()) {
+        if c == '~' {
+            escaped.push('~');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Encode a parameter name. The `.` separating a map prefix from its key is kept as is.
+fn encode_key(key: &str) -> String {
+    match key.split_once('.') {
+        Some((prefix, name)) => format!("{prefix}.{}", urlencoding::encode(name)),
+        None => urlencoding::encode(key).into_owned(),
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+fn unsupported<T>(what: &str) -> Result<T, Error> {
+    Err(Error(format!(
+        "{what} cannot be written to a GA4 querystring"
+    )))
+}
+
+/// Serializes the top-level payload struct into ordered key/value pairs.
+struct PayloadSerializer;
+
+struct PayloadFields {
+    pairs: Vec<(String, String)>,
+}
+
+impl ser::SerializeStruct for PayloadFields {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        match value.serialize(ValueSerializer)? {
+            Value::Skip => {}
+            Value::Scalar(value) => self.pairs.push((key.to_string(), value)),
+            Value::Map(mut entries) => {
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                self.pairs.extend(
+                    entries
+                        .into_iter()
+                        .map(|(name, value)| (format!("{key}.{name}"), value)),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.pairs)
+    }
+}
+
+impl ser::Serializer for PayloadSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+    type SerializeSeq = Impossible<Self::Ok, Error>;
+    type SerializeTuple = Impossible<Self::Ok, Error>;
+    type SerializeTupleStruct = Impossible<Self::Ok, Error>;
+    type SerializeTupleVariant = Impossible<Self::Ok, Error>;
+    type SerializeMap = Impossible<Self::Ok, Error>;
+    type SerializeStruct = PayloadFields;
+    type SerializeStructVariant = Impossible<Self::Ok, Error>;
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<PayloadFields, Error> {
+        Ok(PayloadFields {
+            pairs: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_bool(self, _: bool) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_i8(self, _: i8) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_i16(self, _: i16) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_i32(self, _: i32) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_i64(self, _: i64) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_u8(self, _: u8) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_u16(self, _: u16) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_u32(self, _: u32) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_u64(self, _: u64) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_f32(self, _: f32) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_f64(self, _: f64) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_char(self, _: char) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_str(self, _: &str) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, Error> {
+        unsupported("a bare value")
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        unsupported("a sequence")
+    }
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> {
+        unsupported("a tuple")
+    }
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        unsupported("a tuple")
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        unsupported("a tuple")
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        unsupported("a bare map")
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        unsupported("an enum")
+    }
+}
+
+/// A serialized payload field.
+enum Value {
+    /// `None` fields are not written at all.
+    Skip,
+    Scalar(String),
+    /// Parameter maps such as `ep` or `upn`.
+    Map(Vec<(String, String)>),
+}
+
+/// Serializes a single payload field: strings, numbers, options and string-keyed maps.
+struct ValueSerializer;
+
+struct MapEntries {
+    entries: Vec<(String, String)>,
+    key: Option<String>,
+}
+
+impl ser::SerializeMap for MapEntries {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        match key.serialize(ValueSerializer)? {
+            Value::Scalar(key) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => unsupported("a non-scalar map key"),
+        }
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error("map value without a key".to_string()))?;
+        match value.serialize(ValueSerializer)? {
+            Value::Skip => {}
+            Value::Scalar(value) => self.entries.push((key, value)),
+            Value::Map(_) => return unsupported("a nested map"),
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::Map(self.entries))
+    }
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = Impossible<Value, Error>;
+    type SerializeTuple = Impossible<Value, Error>;
+    type SerializeTupleStruct = Impossible<Value, Error>;
+    type SerializeTupleVariant = Impossible<Value, Error>;
+    type SerializeMap = MapEntries;
+    type SerializeStruct = Impossible<Value, Error>;
+    type SerializeStructVariant = Impossible<Value, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::Scalar(if v { "1" } else { "0" }.to_string()))
+    }
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(Value::Scalar(v.to_string()))
+    }
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        self.serialize_u64(v.into())
+    }
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        self.serialize_u64(v.into())
+    }
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        self.serialize_u64(v.into())
+    }
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        Ok(Value::Scalar(v.to_string()))
+    }
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        // GA4 drops NaN and infinite numbers anyway, don't send them
+        if v.is_finite() {
+            Ok(Value::Scalar(v.to_string()))
+        } else {
+            Ok(Value::Skip)
+        }
+    }
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::Scalar(v.to_string()))
+    }
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(Value::Scalar(v.to_string()))
+    }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Value, Error> {
+        unsupported("bytes")
+    }
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Skip)
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Skip)
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Value, Error> {
+        Ok(Value::Skip)
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(Value::Scalar(variant.to_string()))
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Value, Error> {
+        unsupported("an enum")
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        unsupported("a sequence")
+    }
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> {
+        unsupported("a tuple")
+    }
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        unsupported("a tuple")
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        unsupported("a tuple")
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<MapEntries, Error> {
+        Ok(MapEntries {
+            entries: Vec::with_capacity(len.unwrap_or_default()),
+            key: None,
+        })
+    }
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Error> {
+        unsupported("a nested struct")
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        unsupported("an enum")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    fn payload() -> GaPayload {
+        let mut ga = GaPayload::default();
+        ga.document_location = "https://example.com/".to_string();
+        ga
+    }
+
+    fn params<const N: usize>(entries: [(&str, &str); N]) -> HashMap<String, String> {
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn writes_parameter_maps_with_dotted_keys() {
+        let mut ga = payload();
+        ga.event_parameter_string = Some(params([("page_type", "checkout")]));
+        ga.event_parameter_number = Some(HashMap::from([("page_number".to_string(), 1.0)]));
+        ga.user_property_string = Some(params([("user_id", "123")]));
+        ga.user_property_number = Some(HashMap::from([("user_age".to_string(), 30.5)]));
+
+        let qs = to_querystring(&ga, &[]).unwrap();
+        assert!(qs.contains("&ep.page_type=checkout&"));
+        assert!(qs.contains("&epn.page_number=1&"));
+        assert!(qs.contains("&up.user_id=123&"));
+        assert!(qs.contains("&upn.user_age=30.5"));
+        assert!(!qs.contains('['));
+    }
+
+    #[test]
+    fn sorts_map_keys() {
+        let mut ga = payload();
+        ga.event_parameter_string = Some(params([("b", "2"), ("c", "3"), ("a", "1")]));
+
+        let qs = to_querystring(&ga, &[]).unwrap();
+        assert!(qs.contains("ep.a=1&ep.b=2&ep.c=3"));
+    }
+
+    #[test]
+    fn skips_empty_options() {
+        let qs = to_querystring(&payload(), &[]).unwrap();
+        assert!(!qs.contains("ep."));
+        assert!(!qs.contains("dr="));
+        assert!(qs.starts_with("v=&tid=&_p=&ul=&cid=&_s=&dl=https%3A%2F%2Fexample.com%2F"));
+    }
+
+    #[test]
+    fn encodes_brackets_in_keys_and_values() {
+        let mut ga = payload();
+        ga.event_parameter_string = Some(params([("filter[size]", "ep[x]=1&up[y]=2")]));
+
+        let qs = to_querystring(&ga, &[]).unwrap();
+        assert!(qs.contains("ep.filter%5Bsize%5D=ep%5Bx%5D%3D1%26up%5By%5D%3D2"));
+    }
+
+    #[test]
+    fn does_not_double_encode() {
+        let mut ga = payload();
+        ga.event_parameter_string = Some(params([("query", "a%20b c")]));
+
+        let qs = to_querystring(&ga, &[]).unwrap();
+        assert!(qs.contains("ep.query=a%2520b%20c"));
+    }
+
+    #[test]
+    fn skips_non_finite_numbers() {
+        let mut ga = payload();
+        ga.event_parameter_number = Some(HashMap::from([
+            ("nan".to_string(), f64::NAN),
+            ("value".to_string(), 12.5),
+        ]));
+
+        let qs = to_querystring(&ga, &[]).unwrap();
+        assert!(!qs.contains("nan"));
+        assert!(qs.contains("epn.value=12.5"));
+    }
+
+    #[test]
+    fn appends_items() {
+        let items = vec![
+            Product {
+                sku: Some("SKU_1".to_string()),
+                name: Some("Tee".to_string()),
+                price: Some("10.5".to_string()),
+                custom_parameters: Some(vec![("color".to_string(), "green".to_string())]),
+                ..Product::default()
+            },
+            Product {
+                sku: Some("SKU_2".to_string()),
+                quantity: Some("2".to_string()),
+                ..Product::default()
+            },
+        ];
+
+        let qs = to_querystring(&payload(), &items).unwrap();
+        assert!(qs.ends_with("&pr1=idSKU_1~nmTee~pr10.5~k0color~v0green&pr2=idSKU_2~qt2"));
+    }
+
+    #[test]
+    fn batch_splits_shared_and_event_parameters() {
+        let mut ga = payload();
+        ga.currency_code = Some("EUR".to_string());
+        ga.event_parameter_string = Some(HashMap::from([(
+            "search_term".to_string(),
+            "red shoes".to_string(),
+        )]));
+        let extra = vec![vec![
+            ("en".to_string(), "view_search_results".to_string()),
+            ("ep.search_term".to_string(), "red shoes".to_string()),
+        ]];
+        let (qs, body) = to_batch(&ga, &[], &extra).unwrap();
+
+        assert!(!qs.contains("en="));
+        assert!(!qs.contains("ep."));
+        assert!(qs.contains("tid="));
+        let lines: Vec<&str> = body.split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("ep.search_term=red%20shoes"));
+        assert!(lines[0].contains("cu=EUR"));
+        assert!(!qs.contains("cu="));
+        assert_eq!(
+            lines[1],
+            "en=view_search_results&ep.search_term=red%20shoes"
+        );
+    }
+
+    #[test]
+    fn encode_item_escapes_separators() {
+        let item = Product {
+            sku: Some("SKU~1".to_string()),
+            name: Some("Shirt ~ Blue\n".to_string()),
+            custom_parameters: Some(vec![("note".to_string(), "~k1x~v1y~".to_string())]),
+            ..Product::default()
+        };
+        assert_eq!(
+            encode_item(&item),
+            "idSKU~~1~nmShirt ~~ Blue~k0note~v0~~k1x~~v1y~~"
+        );
+    }
+
+    #[test]
+    fn encode_item_custom_metrics() {
+        let item = Product {
+            sku: Some("SKU_1".to_string()),
+            custom_parameters: Some(vec![("color".to_string(), "green".to_string())]),
+            custom_metrics: Some(vec![("weight".to_string(), "180".to_string())]),
+            ..Product::default()
+        };
+        assert_eq!(
+            encode_item(&item),
+            "idSKU_1~k0color~v0green~k1weight~vn1180"
+        );
+    }
+
+    #[test]
+    fn encode_item_keeps_parameter_order() {
+        let item = Product {
+            quantity: Some("3".to_string()),
+            sku: Some("SKU_12345".to_string()),
+            category2: Some("Adult".to_string()),
+            brand: Some("Google".to_string()),
+            ..Product::default()
+        };
+        assert_eq!(encode_item(&item), "idSKU_12345~brGoogle~c2Adult~qt3");
+    }
+}
@@ -0,0 +1,77 @@
+//! Sampling of high-volume events, to stay within the GA4 quotas.
+//!
+//! Sampling is deterministic and keyed by client ID: a user is either in or out of the sample, for
+//! all their events, so that their sessions and funnels stay complete. A user in the sample of a
+//! rate is in the sample of all higher rates.
+use anyhow::anyhow;
+
+/// Parse the `sampling_rates` setting: event names and the rate, in ]0, 1], of their events to
+/// send. ex: scroll:0.1,view_item_list:0.25
+pub(crate) fn parse_sampling_rates(rates: &str) -> anyhow::Result<Vec<(String, f64)>> {
+    rates
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (event_name, rate) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid sampling_rates: {}", entry.trim()))?;
+            match rate.trim().parse::<f64>() {
+                Ok(rate) if rate > 0.0 && rate <= 1.0 && !event_name.trim().is_empty() => {
+                    Ok((event_name.trim().to_string(), rate))
+                }
+                _ => Err(anyhow!("Invalid sampling_rates: {}", entry.trim())),
+            }
+        })
+        .collect()
+}
+
+/// Whether the events of a client are in a sample of the given rate.
+pub(crate) fn is_sampled_in(client_id: &str, rate: f64) -> bool {
+    let digest = md5::compute(client_id);
+    let bucket = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    (bucket as f64) < rate * (u32::MAX as f64 + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn sampling_rates() {
+        assert_eq!(
+            parse_sampling_rates(" scroll:0.1, view_item_list : 1 ,").unwrap(),
+            vec![
+                ("scroll".to_string(), 0.1),
+                ("view_item_list".to_string(), 1.0)
+            ]
+        );
+        assert!(parse_sampling_rates("").unwrap().is_empty());
+        assert_eq!(
+            parse_sampling_rates("scroll:0").unwrap_err().to_string(),
+            "Invalid sampling_rates: scroll:0"
+        );
+        assert!(parse_sampling_rates("scroll:1.5").is_err());
+        assert!(parse_sampling_rates("scroll").is_err());
+        assert!(parse_sampling_rates(":0.5").is_err());
+    }
+
+    #[test]
+    fn sampling_is_deterministic_and_nested() {
+        let client_ids: Vec<String> = (0..1000).map(|i| format!("{i}.1735000000")).collect();
+        let sampled = |rate: f64| -> Vec<&String> {
+            client_ids
+                .iter()
+                .filter(|client_id| is_sampled_in(client_id, rate))
+                .collect()
+        };
+
+        let tenth = sampled(0.1);
+        let half = sampled(0.5);
+        assert!((70..130).contains(&tenth.len()), "{}", tenth.len());
+        assert!((450..550).contains(&half.len()), "{}", half.len());
+        assert!(tenth.iter().all(|client_id| half.contains(client_id)));
+        assert_eq!(sampled(1.0).len(), 1000);
+        assert_eq!(sampled(0.1), tenth);
+    }
+}
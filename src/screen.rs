@@ -0,0 +1,100 @@
+//! Screen resolution (`sr`) and viewport size (`vp`).
+use anyhow::anyhow;
+
+/// Pixels of the screen resolution, from the `screen_resolution_unit` setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum ScreenResolutionUnit {
+    /// CSS pixels, like gtag.js (`window.screen`)
+    #[default]
+    Css,
+    /// Device pixels: CSS pixels times the device pixel ratio (`screen_density`)
+    Device,
+}
+
+impl ScreenResolutionUnit {
+    pub(crate) fn parse(unit: &str) -> anyhow::Result<Self> {
+        match unit.trim() {
+            "" | "css" => Ok(ScreenResolutionUnit::Css),
+            "device" => Ok(ScreenResolutionUnit::Device),
+            other => Err(anyhow!("Invalid screen_resolution_unit: {other}")),
+        }
+    }
+}
+
+/// Screen resolution, in the unit of the settings. ex: (1512, 982, 2.0, Device) -> 3024x1964
+/// The resolution stays in CSS pixels when the device pixel ratio is unknown.
+pub(crate) fn screen_resolution(
+    width: i32,
+    height: i32,
+    density: f32,
+    unit: ScreenResolutionUnit,
+) -> Option<String> {
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+    let ratio = match unit {
+        ScreenResolutionUnit::Device if density.is_finite() && density > 0.0 => density as f64,
+        _ => 1.0,
+    };
+    let scale = |pixels: i32| (pixels as f64 * ratio).round() as u32;
+    Some(format!("{}x{}", scale(width), scale(height)))
+}
+
+/// Parse a size, in pixels. ex: 1280x720, 1280 X 720
+pub(crate) fn parse_size(size: &str) -> Option<String> {
+    let (width, height) = size
+        .trim()
+        .to_lowercase()
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.trim().parse::<u32>().ok()?, h.trim().parse::<u32>().ok()?)))?;
+    (width > 0 && height > 0).then(|| format!("{width}x{height}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn screen_resolutions() {
+        let css = ScreenResolutionUnit::Css;
+        let device = ScreenResolutionUnit::Device;
+        assert_eq!(
+            screen_resolution(1512, 982, 2.0, css).as_deref(),
+            Some("1512x982")
+        );
+        assert_eq!(
+            screen_resolution(1512, 982, 2.0, device).as_deref(),
+            Some("3024x1964")
+        );
+        assert_eq!(
+            screen_resolution(412, 915, 2.625, device).as_deref(),
+            Some("1082x2402")
+        );
+        assert_eq!(
+            screen_resolution(1512, 982, 0.0, device).as_deref(),
+            Some("1512x982")
+        );
+        assert_eq!(screen_resolution(0, 982, 2.0, css), None);
+        assert_eq!(screen_resolution(1512, -1, 2.0, css), None);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size(" 1280 X 720 ").as_deref(), Some("1280x720"));
+        assert_eq!(parse_size("1280x0"), None);
+        assert_eq!(parse_size("1280x720.5"), None);
+        assert_eq!(parse_size("wide"), None);
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(
+            ScreenResolutionUnit::parse("device").unwrap(),
+            ScreenResolutionUnit::Device
+        );
+        assert_eq!(
+            ScreenResolutionUnit::parse("dpr").unwrap_err().to_string(),
+            "Invalid screen_resolution_unit: dpr"
+        );
+    }
+}
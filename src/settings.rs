@@ -0,0 +1,579 @@
+//! Component settings, as configured in `edgee.toml` or in the Edgee console.
+use anyhow::anyhow;
+use std::collections::HashMap;
+
+use crate::bot::BotFiltering;
+use crate::currency::ExchangeRates;
+use crate::event_time::StaleEventAction;
+use crate::exports::edgee::components::data_collection::Dict;
+use crate::items::parse_key_aliases;
+use crate::locale::normalize_locale;
+use crate::page::{
+    ContentGroupSource, ReferralExclusion, DEFAULT_PAYMENT_DOMAINS, DEFAULT_SEARCH_QUERY_PARAMETERS,
+};
+use crate::sampling::parse_sampling_rates;
+use crate::screen::ScreenResolutionUnit;
+use crate::traffic::{IpRange, DEFAULT_INTERNAL_TRAFFIC_TYPE};
+use crate::user_id::UserIdPolicy;
+
+/// Language of gtag.js when the browser doesn't have one.
+const DEFAULT_LANGUAGE: &str = "en";
+
+/// Number of custom parameters GA4 accepts per item.
+const DEFAULT_ITEM_PARAMETER_LIMIT: usize = 27;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Settings {
+    /// GA4 Measurement ID. ex: G-XXXXXXXXXX
+    pub measurement_id: String,
+    /// When an e-commerce event has both an explicit `value` property and items with prices, keep
+    /// the explicit value (true) or replace it with the value computed from the items (false).
+    pub explicit_value_override: bool,
+    /// Reporting currency and exchange rates, to convert revenue into the GA4 property currency.
+    pub exchange_rates: Option<ExchangeRates>,
+    /// Maximum age, in seconds, of the purchases whose hits get an `event_id` and `_rnd` derived
+    /// from the transaction, so that duplicates can be removed downstream. Disabled when 0.
+    pub transaction_dedup_max_age: Option<u64>,
+    /// Maximum number of custom parameters per item. ex: 27, the GA4 limit
+    pub item_parameter_limit: usize,
+    /// Send the numeric custom parameters of items as item-scoped custom metrics.
+    pub item_custom_metrics: bool,
+    /// Product keys sent by your sources, and the Edgee product key they stand for.
+    /// ex: [("sku_code", "sku")]
+    pub product_key_aliases: Vec<(String, String)>,
+    /// What is sent as the GA4 User-ID.
+    pub user_id_policy: UserIdPolicy,
+    /// Salt of the hashed User-ID.
+    pub user_id_salt: String,
+    /// Send the `anonymous_id` as a user property, when it's not the User-ID.
+    pub anonymous_id_user_property: bool,
+    /// Name of the event sent for Edgee user calls. ex: user, login, sign_up or user_engagement
+    pub user_event_name: String,
+    /// Page field sent as the GA4 content group.
+    pub content_group_source: ContentGroupSource,
+    /// Query parameters holding the site search term. ex: q, s, search
+    pub search_query_parameters: Vec<String>,
+    /// Also send a `view_search_results` event for pages with a search term.
+    pub search_results_event: bool,
+    /// Own domains whose referrers are internal. ex: edgee.dev
+    pub referral_exclusion_domains: Vec<String>,
+    /// Payment provider domains whose referrers are internal. ex: stripe.com
+    pub payment_domains: Vec<String>,
+    /// What to do with internal referrers.
+    pub referral_exclusion_action: ReferralExclusion,
+    /// Reuse the client ID and session of a valid `_gl` cross-domain linker.
+    pub accept_incoming_linker: bool,
+    /// IP ranges of internal traffic. ex: 203.0.113.0/24
+    pub internal_ip_ranges: Vec<IpRange>,
+    /// Lowercase user agent substrings of internal traffic. ex: headlesschrome
+    pub internal_user_agents: Vec<String>,
+    /// Traffic type (`tt`) of internal traffic. ex: internal
+    pub internal_traffic_type: String,
+    /// What to do with bot traffic.
+    pub bot_filtering: BotFiltering,
+    /// Lowercase user agent substrings of bots, on top of the known ones. ex: acmemonitor
+    pub bot_user_agents: Vec<String>,
+    /// Also count recent Chrome user agents without client hints as bots.
+    pub bot_require_client_hints: bool,
+    /// Event names and the rate of their events to send. ex: [("scroll", 0.1)]
+    pub sampling_rates: Vec<(String, f64)>,
+    /// Pixels of the screen resolution: CSS or device pixels.
+    pub screen_resolution_unit: ScreenResolutionUnit,
+    /// Language (`ul`) of the clients without a valid locale. ex: en
+    pub default_language: String,
+    /// What to do with events older than the 72 hours GA4 accepts.
+    pub stale_event_action: StaleEventAction,
+    /// All settings, as received
+    pub values: HashMap<String, String>,
+}
+
+impl Settings {
+    pub(crate) fn new(settings: Dict) -> anyhow::Result<Self> {
+        let values: HashMap<String, String> = settings
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        let measurement_id = match values.get("ga_measurement_id") {
+            Some(v) => v,
+            None => return Err(anyhow!("Missing GA Measurement ID")),
+        }
+        .to_string();
+
+        let exchange_rates = match (
+            values.get("reporting_currency"),
+            values.get("exchange_rates"),
+        ) {
+            (Some(currency), table) => Some(ExchangeRates::parse(
+                currency,
+                table.map_or("", |v| v.as_str()),
+            )?),
+            (None, Some(table)) if !table.trim().is_empty() => {
+                return Err(anyhow!("Missing reporting_currency for exchange_rates"))
+            }
+            (None, _) => None,
+        };
+
+        let transaction_dedup_max_age =
+            match values.get("transaction_dedup_max_age").map(|v| v.trim()) {
+                None | Some("") => None,
+                Some(max_age) => match max_age.parse::<u64>() {
+                    Ok(max_age) => Some(max_age).filter(|max_age| *max_age > 0),
+                    Err(_) => return Err(anyhow!("Invalid transaction_dedup_max_age: {max_age}")),
+                },
+            };
+
+        let item_parameter_limit = match values.get("item_parameter_limit").map(|v| v.trim()) {
+            None | Some("") => DEFAULT_ITEM_PARAMETER_LIMIT,
+            Some(limit) => limit
+                .parse::<usize>()
+                .ok()
+                .filter(|limit| *limit > 0)
+                .ok_or_else(|| anyhow!("Invalid item_parameter_limit: {limit}"))?,
+        };
+
+        let user_id_policy =
+            UserIdPolicy::parse(values.get("user_id_policy").map_or("", |v| v.as_str()))?;
+        let user_id_salt = values.get("user_id_salt").cloned().unwrap_or_default();
+        if user_id_policy == UserIdPolicy::HashedUserId && user_id_salt.is_empty() {
+            return Err(anyhow!(
+                "Missing user_id_salt for the hashed_user_id policy"
+            ));
+        }
+
+        let user_event_name = match values.get("user_event_name").map(|v| v.trim()) {
+            None | Some("") => "user".to_string(),
+            Some(name) if is_valid_event_name(name) => name.to_string(),
+            Some(name) => return Err(anyhow!("Invalid user_event_name: {name}")),
+        };
+
+        let content_group_source = ContentGroupSource::parse(
+            values
+                .get("content_group_source")
+                .map_or("", |v| v.as_str()),
+        )?;
+
+        let referral_exclusion_action = ReferralExclusion::parse(
+            values
+                .get("referral_exclusion_action")
+                .map_or("", |v| v.as_str()),
+        )?;
+
+        let bot_filtering =
+            BotFiltering::parse(values.get("bot_filtering").map_or("", |v| v.as_str()))?;
+
+        let sampling_rates =
+            parse_sampling_rates(values.get("sampling_rates").map_or("", |v| v.as_str()))?;
+
+        let screen_resolution_unit = ScreenResolutionUnit::parse(
+            values
+                .get("screen_resolution_unit")
+                .map_or("", |v| v.as_str()),
+        )?;
+
+        let default_language = match values.get("default_language").map(|v| v.trim()) {
+            None | Some("") => DEFAULT_LANGUAGE.to_string(),
+            Some(language) => normalize_locale(language)
+                .ok_or_else(|| anyhow!("Invalid default_language: {language}"))?,
+        };
+
+        let stale_event_action =
+            StaleEventAction::parse(values.get("stale_event_action").map_or("", |v| v.as_str()))?;
+
+        let internal_ip_ranges = values
+            .get("internal_ip_ranges")
+            .map_or("", |v| v.as_str())
+            .split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(IpRange::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Settings {
+            measurement_id,
+            explicit_value_override: parse_bool(&values, "explicit_value_override", true),
+            exchange_rates,
+            transaction_dedup_max_age,
+            item_parameter_limit,
+            item_custom_metrics: parse_bool(&values, "item_custom_metrics", false),
+            product_key_aliases: values
+                .get("product_key_aliases")
+                .map(|aliases| parse_key_aliases(aliases))
+                .unwrap_or_default(),
+            user_id_policy,
+            user_id_salt,
+            anonymous_id_user_property: parse_bool(&values, "anonymous_id_user_property", true),
+            user_event_name,
+            content_group_source,
+            search_query_parameters: match values.get("search_query_parameters") {
+                Some(names) => names
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .collect(),
+                None => DEFAULT_SEARCH_QUERY_PARAMETERS
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+            },
+            search_results_event: parse_bool(&values, "search_results_event", false),
+            referral_exclusion_domains: values
+                .get("referral_exclusion_domains")
+                .map(|domains| parse_domains(domains))
+                .unwrap_or_default(),
+            payment_domains: match values.get("payment_domains") {
+                Some(domains) => parse_domains(domains),
+                None => DEFAULT_PAYMENT_DOMAINS
+                    .iter()
+                    .map(|domain| domain.to_string())
+                    .collect(),
+            },
+            referral_exclusion_action,
+            accept_incoming_linker: parse_bool(&values, "accept_incoming_linker", true),
+            internal_ip_ranges,
+            internal_user_agents: values
+                .get("internal_user_agents")
+                .map(|patterns| {
+                    patterns
+                        .split(',')
+                        .map(|pattern| pattern.trim().to_lowercase())
+                        .filter(|pattern| !pattern.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            internal_traffic_type: match values.get("internal_traffic_type").map(|v| v.trim()) {
+                None | Some("") => DEFAULT_INTERNAL_TRAFFIC_TYPE.to_string(),
+                Some(traffic_type) => traffic_type.to_string(),
+            },
+            bot_filtering,
+            bot_user_agents: values
+                .get("bot_user_agents")
+                .map(|patterns| {
+                    patterns
+                        .split(',')
+                        .map(|pattern| pattern.trim().to_lowercase())
+                        .filter(|pattern| !pattern.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            bot_require_client_hints: parse_bool(&values, "bot_require_client_hints", false),
+            sampling_rates,
+            screen_resolution_unit,
+            default_language,
+            stale_event_action,
+            values,
+        })
+    }
+}
+
+/// GA4 event names start with a letter, only contain letters, digits and underscores, and are at
+/// most 40 characters long.
+fn is_valid_event_name(name: &str) -> bool {
+    name.len() <= 40
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Read a boolean setting, falling back to the default when it's missing or invalid.
+fn parse_bool(values: &HashMap<String, String>, key: &str, default: bool) -> bool {
+    match values.get(key).map(|v| v.trim().to_lowercase()).as_deref() {
+        Some("true") | Some("1") | Some("yes") => true,
+        Some("false") | Some("0") | Some("no") => false,
+        _ => default,
+    }
+}
+
+/// Read a comma separated list of domains, lowercase and without leading dot. ex: .Edgee.dev
+fn parse_domains(domains: &str) -> Vec<String> {
+    domains
+        .split(',')
+        .map(|domain| domain.trim().trim_start_matches('.').to_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::dict;
+
+    #[test]
+    fn missing_measurement_id_fails() {
+        let result = Settings::new(dict(&[("explicit_value_override", "false")]));
+        assert_eq!(result.unwrap_err().to_string(), "Missing GA Measurement ID");
+    }
+
+    #[test]
+    fn defaults() {
+        let settings = Settings::new(dict(&[("ga_measurement_id", "G-1")])).unwrap();
+        assert_eq!(settings.measurement_id, "G-1");
+        assert!(settings.explicit_value_override);
+        assert_eq!(settings.exchange_rates, None);
+        assert_eq!(settings.transaction_dedup_max_age, None);
+        assert_eq!(settings.item_parameter_limit, 27);
+        assert!(!settings.item_custom_metrics);
+        assert!(settings.product_key_aliases.is_empty());
+        assert_eq!(settings.user_id_policy, UserIdPolicy::AnonymousFallback);
+        assert!(settings.anonymous_id_user_property);
+        assert_eq!(settings.user_event_name, "user");
+        assert_eq!(settings.content_group_source, ContentGroupSource::Category);
+        assert_eq!(
+            settings.search_query_parameters,
+            vec!["q", "s", "search", "query", "keyword"]
+        );
+        assert!(!settings.search_results_event);
+        assert!(settings.referral_exclusion_domains.is_empty());
+        assert_eq!(
+            settings.payment_domains,
+            vec![
+                "stripe.com",
+                "paypal.com",
+                "adyen.com",
+                "klarna.com",
+                "mollie.com"
+            ]
+        );
+        assert_eq!(
+            settings.referral_exclusion_action,
+            ReferralExclusion::IgnoreReferrer
+        );
+        assert!(settings.accept_incoming_linker);
+        assert!(settings.internal_ip_ranges.is_empty());
+        assert!(settings.internal_user_agents.is_empty());
+        assert_eq!(settings.internal_traffic_type, "internal");
+        assert_eq!(settings.bot_filtering, BotFiltering::Off);
+        assert!(settings.bot_user_agents.is_empty());
+        assert!(!settings.bot_require_client_hints);
+        assert!(settings.sampling_rates.is_empty());
+        assert_eq!(settings.screen_resolution_unit, ScreenResolutionUnit::Css);
+        assert_eq!(settings.default_language, "en");
+        assert_eq!(settings.stale_event_action, StaleEventAction::Flag);
+    }
+
+    #[test]
+    fn item_parameter_limit() {
+        let limit = |value: &str| {
+            Settings::new(dict(&[
+                ("ga_measurement_id", "G-1"),
+                ("item_parameter_limit", value),
+            ]))
+            .map(|settings| settings.item_parameter_limit)
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(limit(" 10 "), Ok(10));
+        assert_eq!(limit(""), Ok(27));
+        assert_eq!(
+            limit("ten"),
+            Err("Invalid item_parameter_limit: ten".to_string())
+        );
+        assert_eq!(
+            limit("-1"),
+            Err("Invalid item_parameter_limit: -1".to_string())
+        );
+        assert_eq!(
+            limit("0"),
+            Err("Invalid item_parameter_limit: 0".to_string())
+        );
+    }
+
+    #[test]
+    fn exchange_rates() {
+        let settings = Settings::new(dict(&[
+            ("ga_measurement_id", "G-1"),
+            ("reporting_currency", "eur"),
+            ("exchange_rates", "USD:0.9"),
+        ]))
+        .unwrap();
+        let rates = settings.exchange_rates.unwrap();
+        assert_eq!(rates.reporting_currency, "EUR");
+        assert_eq!(rates.factor("USD", "EUR"), Some(0.9));
+    }
+
+    #[test]
+    fn invalid_exchange_rates() {
+        let error = |entries: &[(&str, &str)]| {
+            let mut entries = entries.to_vec();
+            entries.push(("ga_measurement_id", "G-1"));
+            Settings::new(dict(&entries)).unwrap_err().to_string()
+        };
+        assert_eq!(
+            error(&[("reporting_currency", "euro")]),
+            "Invalid reporting_currency: euro"
+        );
+        assert_eq!(
+            error(&[("reporting_currency", "EUR"), ("exchange_rates", "USD:0,9")]),
+            "Invalid exchange_rates: USD:0"
+        );
+        assert_eq!(
+            error(&[("exchange_rates", "USD:0.9")]),
+            "Missing reporting_currency for exchange_rates"
+        );
+    }
+
+    #[test]
+    fn transaction_dedup_max_age() {
+        let max_age = |value: &str| {
+            Settings::new(dict(&[
+                ("ga_measurement_id", "G-1"),
+                ("transaction_dedup_max_age", value),
+            ]))
+            .map(|settings| settings.transaction_dedup_max_age)
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(max_age(" 600 "), Ok(Some(600)));
+        assert_eq!(max_age("0"), Ok(None));
+        assert_eq!(
+            max_age("-1"),
+            Err("Invalid transaction_dedup_max_age: -1".to_string())
+        );
+        assert_eq!(
+            max_age("10m"),
+            Err("Invalid transaction_dedup_max_age: 10m".to_string())
+        );
+    }
+
+    #[test]
+    fn user_id_policy() {
+        let result = Settings::new(dict(&[
+            ("ga_measurement_id", "G-1"),
+            ("user_id_policy", "hashed"),
+        ]));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Invalid user_id_policy: hashed"
+        );
+
+        let result = Settings::new(dict(&[
+            ("ga_measurement_id", "G-1"),
+            ("user_id_policy", "hashed_user_id"),
+        ]));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Missing user_id_salt for the hashed_user_id policy"
+        );
+    }
+
+    #[test]
+    fn user_event_name() {
+        let name = |value: &str| {
+            Settings::new(dict(&[
+                ("ga_measurement_id", "G-1"),
+                ("user_event_name", value),
+            ]))
+            .map(|settings| settings.user_event_name)
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(name(" login "), Ok("login".to_string()));
+        assert_eq!(name(""), Ok("user".to_string()));
+        assert_eq!(
+            name("sign up"),
+            Err("Invalid user_event_name: sign up".to_string())
+        );
+        assert!(name("1st_login").is_err());
+        assert!(name(&"a".repeat(41)).is_err());
+    }
+
+    #[test]
+    fn search_query_parameters() {
+        let parameters = |value: &str| {
+            Settings::new(dict(&[
+                ("ga_measurement_id", "G-1"),
+                ("search_query_parameters", value),
+            ]))
+            .unwrap()
+            .search_query_parameters
+        };
+        assert_eq!(parameters(" term, ,kw"), vec!["term", "kw"]);
+        assert!(parameters("").is_empty());
+    }
+
+    #[test]
+    fn referral_exclusion() {
+        let settings = Settings::new(dict(&[
+            ("ga_measurement_id", "G-1"),
+            ("referral_exclusion_domains", " Edgee.dev, .shop.com,,"),
+            ("referral_exclusion_action", "drop_referrer"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            settings.referral_exclusion_domains,
+            vec!["edgee.dev", "shop.com"]
+        );
+        assert_eq!(
+            settings.referral_exclusion_action,
+            ReferralExclusion::DropReferrer
+        );
+    }
+
+    #[test]
+    fn payment_domains() {
+        let domains = |value: &str| {
+            Settings::new(dict(&[
+                ("ga_measurement_id", "G-1"),
+                ("payment_domains", value),
+            ]))
+            .unwrap()
+            .payment_domains
+        };
+        assert_eq!(
+            domains(" Stripe.com, .pay.example,,"),
+            vec!["stripe.com", "pay.example"]
+        );
+        assert!(domains("").is_empty());
+    }
+
+    #[test]
+    fn internal_traffic() {
+        let internal = Settings::new(dict(&[
+            ("ga_measurement_id", "G-1"),
+            ("internal_ip_ranges", "10.0.0.0/8, ,2001:db8::/32"),
+            ("internal_user_agents", " HeadlessChrome ,"),
+            ("internal_traffic_type", " office "),
+        ]))
+        .unwrap();
+        assert_eq!(internal.internal_ip_ranges.len(), 2);
+        assert_eq!(internal.internal_user_agents, vec!["headlesschrome"]);
+        assert_eq!(internal.internal_traffic_type, "office");
+
+        let result = Settings::new(dict(&[
+            ("ga_measurement_id", "G-1"),
+            ("internal_ip_ranges", "10.0.0.0/8,10.0.0.1/40"),
+        ]));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Invalid internal_ip_ranges: 10.0.0.1/40"
+        );
+    }
+
+    #[test]
+    fn default_language() {
+        let language = |value: &str| {
+            Settings::new(dict(&[
+                ("ga_measurement_id", "G-1"),
+                ("default_language", value),
+            ]))
+            .map(|settings| settings.default_language)
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(language("fr_FR"), Ok("fr-fr".to_string()));
+        assert_eq!(language(" "), Ok("en".to_string()));
+        assert_eq!(
+            language("french"),
+            Err("Invalid default_language: french".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_bool_values() {
+        let settings = Settings::new(dict(&[
+            ("ga_measurement_id", "G-1"),
+            ("explicit_value_override", " False "),
+        ]))
+        .unwrap();
+        assert!(!settings.explicit_value_override);
+
+        let values = HashMap::from([("flag".to_string(), "maybe".to_string())]);
+        assert!(parse_bool(&values, "flag", true));
+        assert!(!parse_bool(&values, "flag", false));
+        assert!(!parse_bool(&values, "missing", false));
+    }
+}
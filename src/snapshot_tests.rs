@@ -0,0 +1,568 @@
+//! Golden-file tests for the generated GA4 hits.
+//!
+//! Every `tests/fixtures/*.json` file describes an Edgee event, the component settings and the
+//! expected `/g/collect` querystring (and the `events` batched in the body, if any). The
+//! querystring is parsed back into key/value pairs (item `prN` strings are split into their own
+//! maps) and compared structurally, so the order in which parameters are written does not matter.
+//!
+//! Run with `UPDATE_SNAPSHOTS=1 cargo test --lib` to rewrite the expectations from the current
+//! output, then review the diff.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use pretty_assertions::Comparison;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::event_time::set_now_micros;
+use crate::exports::edgee::components::data_collection::{
+    Campaign, Client, Consent, Context, Data, Dict, EdgeeRequest, Event, EventType, Guest,
+    PageData, Session, TrackData, UserData,
+};
+use crate::ga_payload::{GaPayload, Product};
+use crate::querystring::to_querystring;
+use crate::GaComponent;
+
+/// Parameters that are random on every hit and can never be compared.
+const ALWAYS_IGNORED: &[&str] = &["_p"];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    #[serde(default)]
+    #[allow(dead_code)]
+    description: String,
+    #[serde(default, deserialize_with = "dict")]
+    settings: Dict,
+    event: FixtureEvent,
+    /// Time the component processes the event, in seconds. Defaults to the event timestamp.
+    #[serde(default)]
+    received_at: Option<i64>,
+    expected: Expected,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Expected {
+    /// Error returned by the component, if the event is expected to be rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Extra parameters to leave out of the comparison.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ignore: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    query: BTreeMap<String, ExpectedValue>,
+    /// Events batched in the request body, one line per event.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    events: Vec<BTreeMap<String, ExpectedValue>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum ExpectedValue {
+    Value(String),
+    Item(BTreeMap<String, String>),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FixtureEvent {
+    #[serde(default = "default_uuid")]
+    uuid: String,
+    #[serde(default)]
+    timestamp: i64,
+    #[serde(default)]
+    consent: Option<FixtureConsent>,
+    data: FixtureData,
+    #[serde(default)]
+    context: FixtureContext,
+}
+
+/// Dictionaries are written as JSON objects; `serde_json/preserve_order` keeps their key order.
+fn dict<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Dict, D::Error> {
+    let map = serde_json::Map::<String, serde_json::Value>::deserialize(deserializer)?;
+    map.into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(value) => Ok((key, value)),
+            other => Err(serde::de::Error::custom(format!(
+                "`{key}` must be a string, got {other}"
+            ))),
+        })
+        .collect()
+}
+
+fn dicts<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Dict>, D::Error> {
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "dict")] Dict);
+    let dicts = Vec::<Wrapper>::deserialize(deserializer)?;
+    Ok(dicts.into_iter().map(|Wrapper(dict)| dict).collect())
+}
+
+fn default_uuid() -> String {
+    "00000000-0000-4000-8000-000000000000".to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FixtureConsent {
+    Pending,
+    Granted,
+    Denied,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum FixtureData {
+    Page(FixturePage),
+    Track(FixtureTrack),
+    User(FixtureUser),
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct FixturePage {
+    name: String,
+    category: String,
+    keywords: Vec<String>,
+    title: String,
+    url: String,
+    path: String,
+    search: String,
+    referrer: String,
+    #[serde(deserialize_with = "dict")]
+    properties: Dict,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FixtureTrack {
+    name: String,
+    #[serde(deserialize_with = "dicts")]
+    products: Vec<Dict>,
+    #[serde(deserialize_with = "dict")]
+    properties: Dict,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct FixtureUser {
+    user_id: String,
+    anonymous_id: String,
+    edgee_id: String,
+    #[serde(deserialize_with = "dict")]
+    properties: Dict,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FixtureContext {
+    page: FixturePage,
+    user: FixtureUser,
+    client: FixtureClient,
+    campaign: FixtureCampaign,
+    session: FixtureSession,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FixtureClient {
+    ip: String,
+    locale: String,
+    timezone: String,
+    user_agent: String,
+    user_agent_architecture: String,
+    user_agent_bitness: String,
+    user_agent_full_version_list: String,
+    user_agent_version_list: String,
+    user_agent_mobile: String,
+    user_agent_model: String,
+    os_name: String,
+    os_version: String,
+    screen_width: i32,
+    screen_height: i32,
+    screen_density: f32,
+    continent: String,
+    country_code: String,
+    country_name: String,
+    region: String,
+    city: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FixtureCampaign {
+    name: String,
+    source: String,
+    medium: String,
+    term: String,
+    content: String,
+    creative_format: String,
+    marketing_tactic: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FixtureSession {
+    session_id: String,
+    previous_session_id: String,
+    session_count: u32,
+    session_start: bool,
+    first_seen: i64,
+    last_seen: i64,
+}
+
+impl From<FixturePage> for PageData {
+    fn from(page: FixturePage) -> Self {
+        PageData {
+            name: page.name,
+            category: page.category,
+            keywords: page.keywords,
+            title: page.title,
+            url: page.url,
+            path: page.path,
+            search: page.search,
+            referrer: page.referrer,
+            properties: page.properties,
+        }
+    }
+}
+
+impl From<FixtureUser> for UserData {
+    fn from(user: FixtureUser) -> Self {
+        UserData {
+            user_id: user.user_id,
+            anonymous_id: user.anonymous_id,
+            edgee_id: user.edgee_id,
+            properties: user.properties,
+        }
+    }
+}
+
+impl From<FixtureEvent> for Event {
+    fn from(event: FixtureEvent) -> Self {
+        let (event_type, data) = match event.data {
+            FixtureData::Page(page) => (EventType::Page, Data::Page(page.into())),
+            FixtureData::Track(track) => (
+                EventType::Track,
+                Data::Track(TrackData {
+                    name: track.name,
+                    products: track.products,
+                    properties: track.properties,
+                }),
+            ),
+            FixtureData::User(user) => (EventType::User, Data::User(user.into())),
+        };
+        let context = event.context;
+        let client = context.client;
+        let campaign = context.campaign;
+        let session = context.session;
+
+        Event {
+            uuid: event.uuid,
+            timestamp: event.timestamp,
+            timestamp_millis: event.timestamp * 1000,
+            timestamp_micros: event.timestamp * 1_000_000,
+            event_type,
+            data,
+            context: Context {
+                page: context.page.into(),
+                user: context.user.into(),
+                client: Client {
+                    ip: client.ip,
+                    locale: client.locale,
+                    timezone: client.timezone,
+                    user_agent: client.user_agent,
+                    user_agent_architecture: client.user_agent_architecture,
+                    user_agent_bitness: client.user_agent_bitness,
+                    user_agent_full_version_list: client.user_agent_full_version_list,
+                    user_agent_version_list: client.user_agent_version_list,
+                    user_agent_mobile: client.user_agent_mobile,
+                    user_agent_model: client.user_agent_model,
+                    os_name: client.os_name,
+                    os_version: client.os_version,
+                    screen_width: client.screen_width,
+                    screen_height: client.screen_height,
+                    screen_density: client.screen_density,
+                    continent: client.continent,
+                    country_code: client.country_code,
+                    country_name: client.country_name,
+                    region: client.region,
+                    city: client.city,
+                },
+                campaign: Campaign {
+                    name: campaign.name,
+                    source: campaign.source,
+                    medium: campaign.medium,
+                    term: campaign.term,
+                    content: campaign.content,
+                    creative_format: campaign.creative_format,
+                    marketing_tactic: campaign.marketing_tactic,
+                },
+                session: Session {
+                    session_id: session.session_id,
+                    previous_session_id: session.previous_session_id,
+                    session_count: session.session_count,
+                    session_start: session.session_start,
+                    first_seen: session.first_seen,
+                    last_seen: session.last_seen,
+                },
+            },
+            consent: event.consent.map(|consent| match consent {
+                FixtureConsent::Pending => Consent::Pending,
+                FixtureConsent::Granted => Consent::Granted,
+                FixtureConsent::Denied => Consent::Denied,
+            }),
+        }
+    }
+}
+
+fn run(event: Event, settings: Dict) -> Result<EdgeeRequest, String> {
+    match event.data {
+        Data::Page(_) => GaComponent::page(event, settings),
+        Data::Track(_) => GaComponent::track(event, settings),
+        Data::User(_) => GaComponent::user(event, settings),
+    }
+}
+
+/// Split a GA4 item string (`id123~nmShirt~k0color~v0green`) into its two-letter prefixes
+/// (or `kN`/`vN` for custom parameters) and values. A doubled `~~` is a literal `~`.
+fn parse_item(item: &str) -> BTreeMap<String, String> {
+    let mut parts = vec![String::new()];
+    let mut chars = item.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            parts.last_mut().unwrap().push(c);
+        } else if chars.peek() == Some(&'~') {
+            chars.next();
+            parts.last_mut().unwrap().push('~');
+        } else {
+            parts.push(String::new());
+        }
+    }
+
+    // custom parameter names start with a letter, so `kN` indexes are unambiguous. Values can
+    // start with digits (`v01000` is the value "1000" of `k0`): use the longest known index.
+    let key_indexes: Vec<&str> = parts
+        .iter()
+        .filter_map(|part| part.strip_prefix('k'))
+        .map(|rest| &rest[..rest.chars().take_while(char::is_ascii_digit).count()])
+        .filter(|index| !index.is_empty())
+        .collect();
+
+    parts
+        .iter()
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let digits = part[1..].chars().take_while(char::is_ascii_digit).count();
+            let prefix_len = match part.chars().next() {
+                Some('k') if digits > 0 => 1 + digits,
+                // numeric value of a custom metric: `vn0180`
+                Some('v') if digits == 0 && part[1..].starts_with('n') => {
+                    let digits = part[2..].chars().take_while(char::is_ascii_digit).count();
+                    2 + (1..=digits)
+                        .rev()
+                        .find(|len| key_indexes.contains(&&part[2..2 + len]))
+                        .unwrap_or(digits)
+                }
+                Some('v') if digits > 0 => {
+                    1 + (1..=digits)
+                        .rev()
+                        .find(|len| key_indexes.contains(&&part[1..1 + len]))
+                        .unwrap_or(digits)
+                }
+                _ => part.len().min(2),
+            };
+            (
+                part[..prefix_len].to_string(),
+                part[prefix_len..].to_string(),
+            )
+        })
+        .collect()
+}
+
+fn is_item_key(key: &str) -> bool {
+    key.len() > 2 && key.starts_with("pr") && key[2..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Decode a querystring component the way GA does (`application/x-www-form-urlencoded`).
+fn decode(component: &str) -> Result<String, String> {
+    urlencoding::decode(&component.replace('+', " "))
+        .map(|decoded| decoded.into_owned())
+        .map_err(|e| format!("{component}: {e}"))
+}
+
+/// Parse a `/g/collect` querystring into comparable values.
+fn parse_querystring(querystring: &str) -> Result<BTreeMap<String, ExpectedValue>, String> {
+    let mut parsed = BTreeMap::new();
+    for pair in querystring.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = decode(key)?;
+        let value = decode(value)?;
+        let value = if is_item_key(&key) {
+            ExpectedValue::Item(parse_item(&value))
+        } else {
+            ExpectedValue::Value(value)
+        };
+        if parsed.insert(key.to_string(), value).is_some() {
+            return Err(format!("parameter `{key}` is present more than once"));
+        }
+    }
+    Ok(parsed)
+}
+
+fn actual_output(event: Event, settings: Dict, ignore: Vec<String>) -> Result<Expected, String> {
+    match run(event, settings) {
+        Err(error) => Ok(Expected {
+            error: Some(error),
+            ignore,
+            ..Expected::default()
+        }),
+        Ok(request) => {
+            let (_, querystring) = request
+                .url
+                .split_once('?')
+                .ok_or_else(|| format!("no querystring in {}", request.url))?;
+            let keep =
+                |key: &String| !ALWAYS_IGNORED.contains(&key.as_str()) && !ignore.contains(key);
+            let mut query = parse_querystring(querystring)?;
+            query.retain(|key, _| keep(key));
+            let mut events = Vec::new();
+            for line in request.body.split("\r\n").filter(|line| !line.is_empty()) {
+                let mut event = parse_querystring(line)?;
+                event.retain(|key, _| keep(key));
+                events.push(event);
+            }
+            Ok(Expected {
+                error: None,
+                ignore,
+                query,
+                events,
+            })
+        }
+    }
+}
+
+fn fixture_paths() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("cannot read {}: {e}", dir.display()))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn check_fixture(path: &Path, update: bool) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let Fixture {
+        settings,
+        event,
+        received_at,
+        expected,
+        ..
+    } = serde_json::from_str(&source).map_err(|e| e.to_string())?;
+    set_now_micros(received_at.unwrap_or(event.timestamp) * 1_000_000);
+    let actual = actual_output(event.into(), settings, expected.ignore.clone())?;
+
+    if expected == actual {
+        return Ok(());
+    }
+    if update {
+        let mut document: serde_json::Value =
+            serde_json::from_str(&source).map_err(|e| e.to_string())?;
+        document["expected"] = serde_json::to_value(&actual).map_err(|e| e.to_string())?;
+        let mut output = serde_json::to_string_pretty(&document).map_err(|e| e.to_string())?;
+        output.push('\n');
+        return fs::write(path, output).map_err(|e| e.to_string());
+    }
+
+    Err(Comparison::new(&expected, &actual).to_string())
+}
+
+#[test]
+fn snapshots_match_fixtures() {
+    let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+    let failures: Vec<String> = fixture_paths()
+        .iter()
+        .filter_map(|path| {
+            check_fixture(path, update)
+                .err()
+                .map(|error| format!("{}:\n{error}", path.display()))
+        })
+        .collect();
+
+    assert!(
+        failures.is_empty(),
+        "{} fixture(s) do not match:\n\n{}",
+        failures.len(),
+        failures.join("\n\n")
+    );
+}
+
+#[test]
+fn parse_item_splits_prefixes_and_custom_parameters() {
+    let item = parse_item(
+        "idSKU_1~nmTee~c2Adult~k0color~v0green~k12size~v12XL~vared~k1qty~v142~k2kg~vn21.5",
+    );
+    let expected: BTreeMap<String, String> = [
+        ("id", "SKU_1"),
+        ("nm", "Tee"),
+        ("c2", "Adult"),
+        ("k0", "color"),
+        ("v0", "green"),
+        ("k12", "size"),
+        ("v12", "XL"),
+        ("va", "red"),
+        ("k1", "qty"),
+        ("v1", "42"),
+        ("k2", "kg"),
+        ("vn2", "1.5"),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect();
+    assert_eq!(item, expected);
+}
+
+#[test]
+fn item_strings_round_trip_adversarial_values() {
+    let adversarial = [
+        "Shirt ~ Blue",
+        "~",
+        "~~",
+        "ends with~",
+        "~starts with",
+        "k0fake~v0value",
+        "a&b=c%20d",
+        "emoji 👕",
+    ];
+    for value in adversarial {
+        let mut product = Product {
+            sku: Some(value.to_string()),
+            name: Some(value.to_string()),
+            variant: Some(value.to_string()),
+            ..Product::default()
+        };
+        product.add_custom_parameter("note", value.to_string());
+
+        let querystring = to_querystring(&GaPayload::default(), &[product]).unwrap();
+        let parsed = parse_querystring(&querystring).unwrap();
+        let expected: BTreeMap<String, String> = [
+            ("id", value),
+            ("nm", value),
+            ("va", value),
+            ("k0", "note"),
+            ("v0", value),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(parsed["pr1"], ExpectedValue::Item(expected), "{value}");
+    }
+}
+
+#[test]
+fn parse_querystring_rejects_duplicate_parameters() {
+    assert!(parse_querystring("en=page_view&en=scroll").is_err());
+}
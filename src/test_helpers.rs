@@ -0,0 +1,44 @@
+//! Helpers shared by the unit tests.
+use crate::exports::edgee::components::data_collection::{Client, Dict};
+use crate::settings::Settings;
+
+/// Settings dictionary, as Edgee passes it to the component.
+pub(crate) fn dict(entries: &[(&str, &str)]) -> Dict {
+    entries
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Valid settings of the `G-1` measurement ID, with the given entries.
+pub(crate) fn settings(entries: &[(&str, &str)]) -> Settings {
+    let mut settings = dict(&[("ga_measurement_id", "G-1")]);
+    settings.extend(dict(entries));
+    Settings::new(settings).unwrap()
+}
+
+/// Client with the given User-Agent, and no other data.
+pub(crate) fn client(user_agent: &str) -> Client {
+    Client {
+        ip: String::new(),
+        locale: String::new(),
+        timezone: String::new(),
+        user_agent: user_agent.to_string(),
+        user_agent_architecture: String::new(),
+        user_agent_bitness: String::new(),
+        user_agent_full_version_list: String::new(),
+        user_agent_version_list: String::new(),
+        user_agent_mobile: String::new(),
+        user_agent_model: String::new(),
+        os_name: String::new(),
+        os_version: String::new(),
+        screen_width: 0,
+        screen_height: 0,
+        screen_density: 0.0,
+        continent: String::new(),
+        country_code: String::new(),
+        country_name: String::new(),
+        region: String::new(),
+        city: String::new(),
+    }
+}
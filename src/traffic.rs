@@ -0,0 +1,141 @@
+//! Traffic type (`tt`): internal traffic, from the office IP ranges or the user agents of our own
+//! tools, can be excluded in GA4 with an internal traffic data filter.
+use std::net::IpAddr;
+
+use anyhow::anyhow;
+
+use crate::exports::edgee::components::data_collection::Client;
+use crate::settings::Settings;
+
+/// Traffic type of GA4 internal traffic data filters.
+pub(crate) const DEFAULT_INTERNAL_TRAFFIC_TYPE: &str = "internal";
+
+/// IP address range in CIDR notation. ex: 203.0.113.0/24, 2001:db8::/32, or a single address
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct IpRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    pub(crate) fn parse(range: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow!("Invalid internal_ip_ranges: {range}");
+        let (address, prefix) = match range.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (range, None),
+        };
+        let network: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u32>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(IpRange { network, prefix })
+    }
+
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => same_prefix(
+                u32::from(network).into(),
+                u32::from(*ip).into(),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(network), u128::from(*ip), 128, self.prefix)
+            }
+            // IPv4 clients may be seen through an IPv4-mapped IPv6 address. ex: ::ffff:203.0.113.7
+            (IpAddr::V4(_), IpAddr::V6(ip)) => ip
+                .to_ipv4_mapped()
+                .is_some_and(|ip| self.contains(&IpAddr::V4(ip))),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+fn same_prefix(network: u128, ip: u128, bits: u32, prefix: u32) -> bool {
+    let shift = bits - prefix;
+    shift == bits || network >> shift == ip >> shift
+}
+
+/// Traffic type of a hit from an internal IP address or user agent, `None` for other hits.
+pub(crate) fn internal_traffic_type(client: &Client, settings: &Settings) -> Option<String> {
+    let internal_ip = client.ip.trim().parse::<IpAddr>().is_ok_and(|ip| {
+        settings
+            .internal_ip_ranges
+            .iter()
+            .any(|range| range.contains(&ip))
+    });
+    let user_agent = client.user_agent.to_lowercase();
+    let internal_user_agent = settings
+        .internal_user_agents
+        .iter()
+        .any(|pattern| user_agent.contains(pattern.as_str()));
+    (internal_ip || internal_user_agent).then(|| settings.internal_traffic_type.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{self, settings};
+
+    fn client(ip: &str, user_agent: &str) -> Client {
+        let mut client = test_helpers::client(user_agent);
+        client.ip = ip.to_string();
+        client
+    }
+
+    fn contains(range: &str, ip: &str) -> bool {
+        IpRange::parse(range)
+            .unwrap()
+            .contains(&ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ip_ranges() {
+        assert!(contains("203.0.113.0/24", "203.0.113.7"));
+        assert!(!contains("203.0.113.0/24", "203.0.114.7"));
+        assert!(contains("203.0.113.7", "203.0.113.7"));
+        assert!(!contains("203.0.113.7", "203.0.113.8"));
+        assert!(contains("0.0.0.0/0", "198.51.100.1"));
+        assert!(contains("2001:db8::/32", "2001:db8:1::42"));
+        assert!(!contains("2001:db8::/32", "2001:db9::42"));
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("::/0", "10.1.2.3"));
+    }
+
+    #[test]
+    fn invalid_ip_ranges() {
+        assert!(IpRange::parse("10.0.0.0/33").is_err());
+        assert!(IpRange::parse("10.0.0/8").is_err());
+        assert_eq!(
+            IpRange::parse("office").unwrap_err().to_string(),
+            "Invalid internal_ip_ranges: office"
+        );
+    }
+
+    #[test]
+    fn internal_traffic() {
+        let settings = settings(&[
+            ("internal_ip_ranges", "203.0.113.0/24, 2001:db8::/32"),
+            ("internal_user_agents", "HeadlessChrome,uptime-check"),
+        ]);
+        assert_eq!(
+            internal_traffic_type(&client("203.0.113.7", "Mozilla/5.0"), &settings).as_deref(),
+            Some("internal")
+        );
+        assert_eq!(
+            internal_traffic_type(&client("198.51.100.1", "acme-Uptime-Check/1.0"), &settings)
+                .as_deref(),
+            Some("internal")
+        );
+        assert_eq!(
+            internal_traffic_type(&client("198.51.100.1", "Mozilla/5.0"), &settings),
+            None
+        );
+        assert_eq!(internal_traffic_type(&client("", ""), &settings), None);
+    }
+}
@@ -0,0 +1,156 @@
+//! Client hints derived from the User-Agent, for the browsers that don't send client hints (Safari,
+//! Firefox) and for the hints the browser didn't send.
+
+/// Platform, mobile flag and browser of a User-Agent.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct UserAgent {
+    /// Platform, with the `Sec-CH-UA-Platform` names. ex: macOS
+    pub platform: Option<String>,
+    /// Only for the platforms whose User-Agent carries the real version. ex: 17.4
+    pub platform_version: Option<String>,
+    pub mobile: bool,
+    /// 32-bit browser on 64-bit Windows
+    pub wow64: bool,
+    /// Browser brand and version, in the `uafvl` format. ex: Firefox;125.0
+    pub full_version_list: Option<String>,
+}
+
+/// Platforms, with the User-Agent token their version follows. Order matters: iOS and Android
+/// User-Agents also mention Mac OS X and Linux. Windows and macOS User-Agents are frozen to
+/// "Windows NT 10.0" and "Mac OS X 10_15_7", their version only comes from client hints.
+const PLATFORMS: [(&str, &str, Option<&str>); 7] = [
+    ("iphone", "iOS", Some("iphone os ")),
+    ("ipad", "iOS", Some("cpu os ")),
+    ("android", "Android", Some("android ")),
+    ("cros", "Chrome OS", None),
+    ("windows", "Windows", None),
+    ("mac os x", "macOS", None),
+    ("linux", "Linux", None),
+];
+
+/// Browsers, with the User-Agent token their version follows. Order matters: Chromium based
+/// browsers also mention Chrome, and all browsers mention Safari.
+const BROWSERS: [(&str, &str); 9] = [
+    ("Edg/", "Microsoft Edge"),
+    ("EdgiOS/", "Microsoft Edge"),
+    ("OPR/", "Opera"),
+    ("SamsungBrowser/", "Samsung Internet"),
+    ("Firefox/", "Firefox"),
+    ("FxiOS/", "Firefox"),
+    ("CriOS/", "Google Chrome"),
+    ("Chrome/", "Google Chrome"),
+    ("Version/", "Safari"),
+];
+
+pub(crate) fn parse_user_agent(user_agent: &str) -> UserAgent {
+    let lowercase = user_agent.to_lowercase();
+    let platform = PLATFORMS
+        .iter()
+        .find(|(token, _, _)| lowercase.contains(token));
+
+    UserAgent {
+        platform: platform.map(|(_, name, _)| name.to_string()),
+        platform_version: platform
+            .and_then(|(_, _, version_token)| version_after(&lowercase, (*version_token)?))
+            .map(|version| version.replace('_', ".")),
+        // like the User-Agent sniffing recommended by browsers vendors
+        mobile: lowercase.contains("mobi"),
+        wow64: lowercase.contains("wow64"),
+        full_version_list: BROWSERS.iter().find_map(|(token, brand)| {
+            let version = version_after(user_agent, token)?;
+            Some(format!("{brand};{version}"))
+        }),
+    }
+}
+
+/// Version following a token. ex: ("Firefox/125.0", "Firefox/") -> 125.0
+fn version_after<'a>(user_agent: &'a str, token: &str) -> Option<&'a str> {
+    let (_, rest) = user_agent.split_once(token)?;
+    let version = rest
+        .split(|c: char| !(c.is_ascii_digit() || c == '.' || c == '_'))
+        .next()?;
+    (!version.is_empty()).then_some(version)
+}
+
+/// `uamb` value of the mobile client hint: 0 or 1. ex: ?1 -> 1, mobile -> 1, false -> 0
+pub(crate) fn normalize_mobile(mobile: &str) -> Option<&'static str> {
+    match mobile.trim().to_lowercase().as_str() {
+        "1" | "?1" | "true" | "yes" | "mobile" => Some("1"),
+        "0" | "?0" | "false" | "no" | "desktop" => Some("0"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn user_agent(
+        platform: &str,
+        platform_version: Option<&str>,
+        mobile: bool,
+        full_version_list: &str,
+    ) -> UserAgent {
+        UserAgent {
+            platform: Some(platform.to_string()),
+            platform_version: platform_version.map(String::from),
+            mobile,
+            wow64: false,
+            full_version_list: Some(full_version_list.to_string()),
+        }
+    }
+
+    #[test]
+    fn safari_on_iphone() {
+        assert_eq!(
+            parse_user_agent("Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1"),
+            user_agent("iOS", Some("17.4"), true, "Safari;17.4")
+        );
+    }
+
+    #[test]
+    fn firefox_on_macos() {
+        assert_eq!(
+            parse_user_agent(
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:125.0) Gecko/20100101 Firefox/125.0"
+            ),
+            user_agent("macOS", None, false, "Firefox;125.0")
+        );
+    }
+
+    #[test]
+    fn chromium_browsers() {
+        assert_eq!(
+            parse_user_agent("Mozilla/5.0 (Linux; Android 14; K) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/24.0 Chrome/117.0.0.0 Mobile Safari/537.36"),
+            user_agent("Android", Some("14"), true, "Samsung Internet;24.0")
+        );
+        let edge = parse_user_agent("Mozilla/5.0 (Windows NT 10.0; WOW64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.2478.80");
+        assert_eq!(edge.platform.as_deref(), Some("Windows"));
+        assert_eq!(edge.platform_version, None);
+        assert!(edge.wow64);
+        assert_eq!(
+            edge.full_version_list.as_deref(),
+            Some("Microsoft Edge;124.0.2478.80")
+        );
+    }
+
+    #[test]
+    fn unknown_user_agents() {
+        assert_eq!(parse_user_agent(""), UserAgent::default());
+        let linux = parse_user_agent("Mozilla/5.0 (X11; Linux x86_64) Gecko/20100101");
+        assert_eq!(linux.platform.as_deref(), Some("Linux"));
+        assert_eq!(linux.platform_version, None);
+        assert_eq!(linux.full_version_list, None);
+    }
+
+    #[test]
+    fn mobile_values() {
+        assert_eq!(normalize_mobile("?1"), Some("1"));
+        assert_eq!(normalize_mobile(" Mobile "), Some("1"));
+        assert_eq!(normalize_mobile("0"), Some("0"));
+        assert_eq!(normalize_mobile("false"), Some("0"));
+        assert_eq!(normalize_mobile("tablet"), None);
+        assert_eq!(normalize_mobile(""), None);
+    }
+}
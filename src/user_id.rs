@@ -0,0 +1,128 @@
+//! User-ID policy: what is sent as the GA4 User-ID (`uid`) and as the `anonymous_id` user property.
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+
+use crate::settings::Settings;
+
+/// Value of the `user_id_policy` setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum UserIdPolicy {
+    /// `user_id`, or `anonymous_id` when there is no `user_id`
+    #[default]
+    AnonymousFallback,
+    /// `user_id` only: anonymous visitors don't have a User-ID
+    UserId,
+    /// SHA-256 hash of the salted `user_id`, in hexadecimal
+    HashedUserId,
+}
+
+impl UserIdPolicy {
+    pub(crate) fn parse(policy: &str) -> anyhow::Result<Self> {
+        match policy.trim() {
+            "" | "anonymous_fallback" => Ok(UserIdPolicy::AnonymousFallback),
+            "user_id" => Ok(UserIdPolicy::UserId),
+            "hashed_user_id" => Ok(UserIdPolicy::HashedUserId),
+            other => Err(anyhow!("Invalid user_id_policy: {other}")),
+        }
+    }
+}
+
+/// GA4 User-ID and `anonymous_id` user property of a user.
+/// The `anonymous_id` user property is sent when it is not already the User-ID, unless disabled.
+pub(crate) fn user_identity(
+    user_id: &str,
+    anonymous_id: &str,
+    settings: &Settings,
+) -> (Option<String>, Option<String>) {
+    let uid = if !user_id.is_empty() {
+        match settings.user_id_policy {
+            UserIdPolicy::HashedUserId => Some(hash_user_id(user_id, &settings.user_id_salt)),
+            _ => Some(user_id.to_string()),
+        }
+    } else if settings.user_id_policy == UserIdPolicy::AnonymousFallback && !anonymous_id.is_empty()
+    {
+        return (Some(anonymous_id.to_string()), None);
+    } else {
+        None
+    };
+
+    let anonymous_id = (settings.anonymous_id_user_property && !anonymous_id.is_empty())
+        .then(|| anonymous_id.to_string());
+    (uid, anonymous_id)
+}
+
+fn hash_user_id(user_id: &str, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(user_id.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::settings;
+    use pretty_assertions::assert_eq;
+
+    fn ids(uid: Option<&str>, anonymous_id: Option<&str>) -> (Option<String>, Option<String>) {
+        (uid.map(String::from), anonymous_id.map(String::from))
+    }
+
+    #[test]
+    fn anonymous_fallback_policy() {
+        let settings = settings(&[]);
+        assert_eq!(
+            user_identity("u-1", "a-1", &settings),
+            ids(Some("u-1"), Some("a-1"))
+        );
+        assert_eq!(user_identity("", "a-1", &settings), ids(Some("a-1"), None));
+        assert_eq!(user_identity("", "", &settings), ids(None, None));
+    }
+
+    #[test]
+    fn user_id_policy() {
+        let settings = settings(&[("user_id_policy", "user_id")]);
+        assert_eq!(
+            user_identity("u-1", "a-1", &settings),
+            ids(Some("u-1"), Some("a-1"))
+        );
+        assert_eq!(user_identity("", "a-1", &settings), ids(None, Some("a-1")));
+    }
+
+    #[test]
+    fn hashed_user_id_policy() {
+        let settings = settings(&[
+            ("user_id_policy", "hashed_user_id"),
+            ("user_id_salt", "pepper"),
+        ]);
+        assert_eq!(
+            user_identity("u-1", "a-1", &settings),
+            ids(
+                Some("7831a58e9abe67a932bdae28df37f6b9fcb77a516c493bc8ce8b08ffb9f180b1"),
+                Some("a-1")
+            )
+        );
+        assert_eq!(user_identity("", "a-1", &settings), ids(None, Some("a-1")));
+    }
+
+    #[test]
+    fn anonymous_id_user_property_can_be_disabled() {
+        let settings = settings(&[
+            ("user_id_policy", "user_id"),
+            ("anonymous_id_user_property", "false"),
+        ]);
+        assert_eq!(
+            user_identity("u-1", "a-1", &settings),
+            ids(Some("u-1"), None)
+        );
+    }
+
+    #[test]
+    fn invalid_policy() {
+        assert!(UserIdPolicy::parse("hashed").is_err());
+        assert_eq!(
+            UserIdPolicy::parse(" user_id ").unwrap(),
+            UserIdPolicy::UserId
+        );
+    }
+}